@@ -0,0 +1,90 @@
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::info;
+
+use crate::context::{self, RequestContext, REQUEST_ID_HEADER};
+use crate::metrics;
+
+/// Middleware giving every request a `RequestContext`, then logging and
+/// recording metrics about it uniformly once it is answered: the route,
+/// institute, status, and the time spent in total and waiting for Kreta.
+pub struct AccessLog;
+
+impl<S, B> Transform<S> for AccessLog
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware { service })
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let request_started = Instant::now();
+        let request_context = RequestContext::from_request(&req);
+        let logged_context = request_context.clone();
+        let method = req.method().to_string();
+
+        let response = self.service.call(req);
+
+        Box::pin(context::scope(request_context, async move {
+            let mut res = response.await?;
+
+            let elapsed = request_started.elapsed();
+            let status = res.status().as_u16();
+            let route = metrics::route_label(res.request().path());
+            metrics::observe_request(&route, status, elapsed);
+
+            info!(
+                "{} {} done for {} with status={} total_ms={} upstream_ms={} retries={}",
+                method,
+                route,
+                logged_context.institute.as_deref().unwrap_or("-"),
+                status,
+                elapsed.as_millis(),
+                logged_context.upstream_time().as_millis(),
+                logged_context.upstream_retries()
+            );
+
+            if let Ok(request_id) = HeaderValue::from_str(&logged_context.id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), request_id);
+            }
+
+            Ok(res)
+        }))
+    }
+}
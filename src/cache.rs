@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{HeaderValue, CACHE_CONTROL};
+use actix_web::HttpResponse;
+
+use crate::config::{self, CacheConfig};
+use crate::context;
+use crate::metrics;
+use crate::store::token_hash;
+
+/// Kinds of Kreta data, each cached for its own time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resource {
+    Schedule,
+    Profile,
+    Tasks,
+    Homework,
+}
+
+impl Resource {
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Schedule => "schedule",
+            Resource::Profile => "profile",
+            Resource::Tasks => "tasks",
+            Resource::Homework => "homework",
+        }
+    }
+
+    pub fn ttl(self, config: &CacheConfig) -> Duration {
+        Duration::from_secs(match self {
+            Resource::Schedule => config.schedule_ttl_secs,
+            Resource::Profile => config.profile_ttl_secs,
+            Resource::Tasks => config.tasks_ttl_secs,
+            Resource::Homework => config.homework_ttl_secs,
+        })
+    }
+}
+
+/// Identifies a cached answer by the student's token, the Kreta endpoint and
+/// the parameters of the call.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    token_hash: String,
+    endpoint: &'static str,
+    params: String,
+}
+
+impl CacheKey {
+    pub fn new(token: &str, endpoint: &'static str, params: &str) -> CacheKey {
+        CacheKey {
+            token_hash: token_hash(token),
+            endpoint,
+            params: params.to_string(),
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    expires: Instant,
+}
+
+/// Answers of Kreta kept for a while, so students refreshing their app don't
+/// reach Kreta on every request.
+pub struct ResponseCache<V> {
+    entries: Mutex<HashMap<CacheKey, Entry<V>>>,
+    max_entries: usize,
+}
+
+impl<V: Clone> ResponseCache<V> {
+    pub fn new(max_entries: usize) -> ResponseCache<V> {
+        ResponseCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    fn get_at(&self, key: &CacheKey, now: Instant) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires > now => Some(entry.value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert_at(&self, key: CacheKey, value: V, ttl: Duration, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                value,
+                expires: now + ttl,
+            },
+        );
+    }
+
+    /// Looks up a fresh answer, unless caching is disabled or the client
+    /// asked for fresh data with `?refresh=true`.
+    pub fn get(&self, resource: Resource, key: &CacheKey) -> Option<V> {
+        if !config::get().cache.enabled || context::refresh() {
+            return None;
+        }
+
+        let value = self.get_at(key, Instant::now());
+        metrics::count_cache_lookup(resource.name(), value.is_some());
+        value
+    }
+
+    pub fn insert(&self, resource: Resource, key: CacheKey, value: V) {
+        let cache_config = &config::get().cache;
+        if cache_config.enabled {
+            self.insert_at(key, value, resource.ttl(cache_config), Instant::now());
+        }
+    }
+}
+
+/// Tells clients how long they may reuse a response made of `resource`.
+/// Responses hold the data of a single student, so only private caches may
+/// store them.
+pub fn with_cache_control(mut response: HttpResponse, resource: Resource) -> HttpResponse {
+    let cache_config = &config::get().cache;
+    let cache_control = if cache_config.enabled {
+        format!("private, max-age={}", resource.ttl(cache_config).as_secs())
+    } else {
+        String::from("private, no-cache")
+    };
+
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        response.headers_mut().insert(CACHE_CONTROL, cache_control);
+    }
+    response
+}
+
+#[cfg(test)]
+mod cache_test {
+    use super::*;
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = ResponseCache::new(10);
+        let key = CacheKey::new("token", "Student", "/mapi/api/v1/Student");
+        let now = Instant::now();
+
+        cache.insert_at(key.clone(), 1, Duration::from_secs(60), now);
+        assert_eq!(cache.get_at(&key, now + Duration::from_secs(59)), Some(1));
+        assert_eq!(cache.get_at(&key, now + Duration::from_secs(60)), None);
+
+        let other_student = CacheKey::new("other", "Student", "/mapi/api/v1/Student");
+        cache.insert_at(key.clone(), 1, Duration::from_secs(60), now);
+        assert_eq!(cache.get_at(&other_student, now), None);
+    }
+
+    #[test]
+    fn test_full_cache_evicts_soonest_expiring() {
+        let cache = ResponseCache::new(2);
+        let key = |params: &str| CacheKey::new("token", "Lesson", params);
+        let now = Instant::now();
+
+        cache.insert_at(key("a"), 1, Duration::from_secs(30), now);
+        cache.insert_at(key("b"), 2, Duration::from_secs(10), now);
+        cache.insert_at(key("c"), 3, Duration::from_secs(20), now);
+
+        assert_eq!(cache.get_at(&key("a"), now), Some(1));
+        assert_eq!(cache.get_at(&key("b"), now), None);
+        assert_eq!(cache.get_at(&key("c"), now), Some(3));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Europe::Budapest;
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::error::KretaError;
+use crate::institute::InstituteCode;
+use crate::requests::{get_every_homework, get_profile, get_schedule, get_tasks};
+use crate::store::StudentStore;
+
+/// Lessons of the next this many days are watched.
+const LESSON_DAYS: i64 = 14;
+/// Tasks announced for the next this many days are watched.
+const TASK_DAYS: i64 = 60;
+/// Homework given in the last this many days is watched, a bit less than the
+/// month `get_homework` fetches.
+const HOMEWORK_DAYS: i64 = 27;
+/// Detected changes kept for streams falling behind, which then catch up
+/// from the store.
+const DETECTED_CAPACITY: usize = 256;
+
+/// Changes of every student as they are detected, for the event streams.
+static DETECTED: Lazy<broadcast::Sender<DetectedChange>> =
+    Lazy::new(|| broadcast::channel(DETECTED_CAPACITY).0);
+
+/// One detection at a time per student, so the snapshots of a student are
+/// compared in the order they were fetched.
+static DETECTIONS: Lazy<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Data of a student watched for changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Watched {
+    Grades,
+    Notes,
+    Lessons,
+    Tasks,
+    Homework,
+}
+
+impl Watched {
+    pub fn name(self) -> &'static str {
+        match self {
+            Watched::Grades => "grades",
+            Watched::Notes => "notes",
+            Watched::Lessons => "lessons",
+            Watched::Tasks => "tasks",
+            Watched::Homework => "homework",
+        }
+    }
+
+    /// Fields identifying an item, so an item with other fields changed is
+    /// reported as modified rather than removed and added.
+    fn key_fields(self) -> &'static [&'static str] {
+        match self {
+            Watched::Grades => &["subject", "creationDate", "gradeType", "topic"],
+            Watched::Lessons => &["date", "periodNumber", "className"],
+            Watched::Notes | Watched::Tasks | Watched::Homework => &["id"],
+        }
+    }
+
+    /// Field holding the date of an item.
+    fn date_field(self) -> &'static str {
+        match self {
+            Watched::Grades | Watched::Lessons => "date",
+            Watched::Notes | Watched::Homework => "creationDate",
+            Watched::Tasks => "dueDate",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Change {
+    pub resource: Watched,
+    pub kind: ChangeKind,
+    /// The item as it is now, or as it was before it was removed.
+    pub item: Value,
+    /// The item before it was modified.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<Value>,
+}
+
+/// A change with the time it was detected, numbered in the order of detection.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEvent {
+    pub id: i64,
+    pub detected_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+/// A change with the student it belongs to.
+#[derive(Debug, Clone)]
+pub struct DetectedChange {
+    pub student: String,
+    pub event: ChangeEvent,
+}
+
+/// Receives the changes of every student detected from now on.
+pub fn watch() -> broadcast::Receiver<DetectedChange> {
+    DETECTED.subscribe()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesResponse {
+    pub checked_at: DateTime<Utc>,
+    /// Pass it as `after` next time to only get the changes recorded later.
+    pub cursor: i64,
+    pub changes: Vec<ChangeEvent>,
+}
+
+/// The items of a watched resource with the dates they were fetched for,
+/// as the lessons, tasks and homework of Kreta are asked for a date range.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    from: Option<String>,
+    to: Option<String>,
+    items: Vec<Value>,
+}
+
+impl Snapshot {
+    fn new<T: Serialize>(
+        items: &[T],
+        from: Option<String>,
+        to: Option<String>,
+    ) -> Result<Snapshot, KretaError> {
+        let items = items
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()
+            .map_err(|err| KretaError::SerializationError(err.to_string()))?;
+        Ok(Snapshot { from, to, items })
+    }
+}
+
+/// Compares two snapshots of `resource`. Only items dated within the range
+/// both snapshots were fetched for are compared, so lessons leaving the
+/// watched days aren't reported as removed.
+pub fn diff(resource: Watched, previous: &Snapshot, current: &Snapshot) -> Vec<Change> {
+    let from = previous.from.as_ref().max(current.from.as_ref());
+    let to = match (&previous.to, &current.to) {
+        (Some(previous), Some(current)) => Some(previous.min(current)),
+        (previous, current) => previous.as_ref().or_else(|| current.as_ref()),
+    };
+    let in_range = |item: &Value| {
+        let date = item
+            .get(resource.date_field())
+            .and_then(Value::as_str)
+            .unwrap_or("");
+        let after_from = from.map_or(true, |from| date >= from.as_str());
+        let before_to = to.map_or(true, |to| date <= to.as_str());
+        after_from && before_to
+    };
+
+    let previous_items: HashMap<String, &Value> = keyed(resource, &previous.items, &in_range)
+        .into_iter()
+        .collect();
+    let current_items = keyed(resource, &current.items, &in_range);
+
+    let mut changes = Vec::new();
+    for (key, item) in &current_items {
+        match previous_items.get(key) {
+            None => changes.push(Change {
+                resource,
+                kind: ChangeKind::Added,
+                item: (*item).clone(),
+                previous: None,
+            }),
+            Some(previous) if previous != item => changes.push(Change {
+                resource,
+                kind: ChangeKind::Modified,
+                item: (*item).clone(),
+                previous: Some((*previous).clone()),
+            }),
+            Some(_) => {}
+        }
+    }
+
+    for (key, item) in keyed(resource, &previous.items, &in_range) {
+        if !current_items.iter().any(|(current, _)| *current == key) {
+            changes.push(Change {
+                resource,
+                kind: ChangeKind::Removed,
+                item: item.clone(),
+                previous: None,
+            });
+        }
+    }
+    changes
+}
+
+/// Pairs the items in range with their keys, numbering items with the same key.
+fn keyed<'a>(
+    resource: Watched,
+    items: &'a [Value],
+    in_range: &dyn Fn(&Value) -> bool,
+) -> Vec<(String, &'a Value)> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    items
+        .iter()
+        .filter(|item| in_range(item))
+        .map(|item| {
+            let key = resource
+                .key_fields()
+                .iter()
+                .map(|field| item.get(field).map(Value::to_string).unwrap_or_default())
+                .collect::<Vec<_>>()
+                .join("|");
+            let count = seen.entry(key.clone()).or_insert(0);
+            *count += 1;
+            (format!("{}#{}", key, count), item)
+        })
+        .collect()
+}
+
+/// Fetches the watched data of the student of `token` and records how it
+/// changed since the last detection. The first detection of a student only
+/// saves the data. Returns the student with the changes found.
+pub async fn detect(
+    store: &StudentStore,
+    token: &str,
+    institute: &InstituteCode,
+) -> Result<(String, Vec<ChangeEvent>), KretaError> {
+    if !store.is_enabled() {
+        return Err(KretaError::StoreDisabled);
+    }
+
+    let student = match store.known_student(token).await {
+        Some(student) => student,
+        None => {
+            let profile = get_profile(token, institute).await?.refine();
+            store.remember_profile(token, institute, profile.id).await
+        }
+    };
+    let detection = detection_lock(&student);
+    let _detecting = detection.lock().await;
+
+    // Kreta dates are Hungarian, around midnight the UTC date is a day behind
+    let today = Utc::now().with_timezone(&Budapest).date();
+    let day = |days: i64| {
+        (today + Duration::days(days))
+            .format("%Y-%m-%d")
+            .to_string()
+    };
+
+    let profile = get_profile(token, institute).await?.refine();
+    let lessons = get_schedule(token.to_string(), institute, day(0), day(LESSON_DAYS)).await?;
+    let tasks = get_tasks(token, institute, &day(0), &day(TASK_DAYS)).await?;
+
+    let mut snapshots = vec![
+        (Watched::Grades, Snapshot::new(&profile.grades, None, None)?),
+        (Watched::Notes, Snapshot::new(&profile.notes, None, None)?),
+        (
+            Watched::Lessons,
+            Snapshot::new(&lessons, Some(day(0)), Some(day(LESSON_DAYS)))?,
+        ),
+        (
+            Watched::Tasks,
+            Snapshot::new(&tasks, Some(day(0)), Some(day(TASK_DAYS)))?,
+        ),
+    ];
+    // Homework is fetched one by one, a single failure would be taken for a
+    // removal, so it is compared next time instead
+    match get_every_homework(token.to_string(), institute).await {
+        Ok(homework) => snapshots.push((
+            Watched::Homework,
+            Snapshot::new(&homework, Some(day(-HOMEWORK_DAYS)), Some(day(0)))?,
+        )),
+        Err(err) => warn!("Homework of {} couldn't be checked: {}", student, err),
+    }
+
+    let mut events = Vec::new();
+    for (resource, current) in snapshots {
+        let snapshot = format!("changes:{}", resource.name());
+        events.extend(
+            store
+                .update_snapshot(&student, &snapshot, move |previous: Option<Snapshot>| {
+                    let changes = previous
+                        .map(|previous| diff(resource, &previous, &current))
+                        .unwrap_or_default();
+                    (current, changes)
+                })
+                .await?,
+        );
+    }
+
+    for event in &events {
+        // Fails only when no stream is open
+        let _ = DETECTED.send(DetectedChange {
+            student: student.clone(),
+            event: event.clone(),
+        });
+    }
+    Ok((student, events))
+}
+
+fn detection_lock(student: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut detections = DETECTIONS.lock().unwrap();
+    if !detections.contains_key(student) {
+        // Only the students being checked right now are kept
+        detections.retain(|_, detection| Arc::strong_count(detection) > 1);
+    }
+    detections
+        .entry(student.to_string())
+        .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+        .clone()
+}
+
+#[cfg(test)]
+mod changes_test {
+    use super::*;
+    use serde_json::json;
+
+    fn snapshot(from: &str, to: &str, items: Vec<Value>) -> Snapshot {
+        Snapshot {
+            from: Some(from.to_string()),
+            to: Some(to.to_string()),
+            items,
+        }
+    }
+
+    fn lesson(date: &str, period_number: i8, cancelled: bool) -> Value {
+        json!({
+            "date": date,
+            "periodNumber": period_number,
+            "className": "9.A",
+            "subject": "Matematika",
+            "cancelled": cancelled,
+        })
+    }
+
+    #[test]
+    fn test_lesson_changes() {
+        let previous = snapshot(
+            "2020-02-03",
+            "2020-02-17",
+            vec![
+                lesson("2020-02-03", 1, false),
+                lesson("2020-02-04", 1, false),
+                lesson("2020-02-04", 2, false),
+            ],
+        );
+        let current = snapshot(
+            "2020-02-04",
+            "2020-02-18",
+            vec![
+                lesson("2020-02-04", 1, true),
+                lesson("2020-02-05", 3, false),
+                lesson("2020-02-18", 1, false),
+            ],
+        );
+
+        let changes = diff(Watched::Lessons, &previous, &current);
+        let kinds: Vec<(ChangeKind, &Value)> = changes
+            .iter()
+            .map(|change| (change.kind, &change.item))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (ChangeKind::Modified, &lesson("2020-02-04", 1, true)),
+                (ChangeKind::Added, &lesson("2020-02-05", 3, false)),
+                (ChangeKind::Removed, &lesson("2020-02-04", 2, false)),
+            ]
+        );
+        assert_eq!(changes[0].previous, Some(lesson("2020-02-04", 1, false)));
+    }
+
+    #[test]
+    fn test_same_keys_are_numbered() {
+        let grade = json!({"subject": "Fizika", "creationDate": "2020-02-03", "grade": "5"});
+        let previous = Snapshot {
+            from: None,
+            to: None,
+            items: vec![grade.clone()],
+        };
+        let current = Snapshot {
+            from: None,
+            to: None,
+            items: vec![grade.clone(), grade.clone()],
+        };
+
+        let changes = diff(Watched::Grades, &previous, &current);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::Added);
+        assert!(diff(Watched::Grades, &current, &current).is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+use actix_web::dev::{BodyEncoding, BodySize, MessageBody, ServiceResponse};
+use actix_web::http::header::ContentEncoding;
+
+pub const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// Opts the response out of compression when its body is known to be smaller
+/// than `min_size` bytes, where the encoding overhead isn't worth it.
+pub fn skip_small_body<B: MessageBody>(res: &mut ServiceResponse<B>, min_size: u64) {
+    let size = match res.response().body().size() {
+        BodySize::Sized(size) => size as u64,
+        BodySize::Sized64(size) => size,
+        BodySize::None | BodySize::Empty => 0,
+        BodySize::Stream => return,
+    };
+
+    if size < min_size {
+        res.response_mut().encoding(ContentEncoding::Identity);
+    }
+}
@@ -0,0 +1,689 @@
+use std::collections::HashMap;
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use derive_more::Display;
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+use crate::compression::DEFAULT_MIN_SIZE;
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+static CONFIG_PATH_ENV: &str = "KRETA_PROXY_CONFIG";
+static INSTITUTE_PLACEHOLDER: &str = "{institute}";
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "Config file {} couldn't be read: {}", _0, _1)]
+    Read(String, std::io::Error),
+    #[display(fmt = "Config file {} couldn't be parsed: {}", _0, _1)]
+    Parse(String, toml::de::Error),
+    #[display(fmt = "Environment variable {} couldn't be parsed: {}", _0, _1)]
+    Environment(&'static str, String),
+    #[display(fmt = "Invalid configuration: {}", _0)]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub upstream: UpstreamConfig,
+    pub cache: CacheConfig,
+    pub compression: CompressionConfig,
+    pub log: LogConfig,
+    pub cors: CorsConfig,
+    pub health: HealthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub store: StoreConfig,
+    pub subscriptions: SubscriptionsConfig,
+    pub events: EventsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    /// Number of worker threads, defaults to the number of logical cpus.
+    pub workers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Base url of the school specific Kreta servers, where `{institute}`
+    /// is replaced with the institute code.
+    pub institute_url: String,
+    /// Base url of the global Kreta api listing the institutes.
+    pub global_api_url: String,
+    pub global_api_key: String,
+    pub connect_timeout_secs: u64,
+    pub timeout_secs: u64,
+    /// Number of calls to Kreta in flight at once.
+    pub max_concurrent: usize,
+    /// Number of calls to a single institute in flight at once.
+    pub max_concurrent_per_institute: usize,
+    /// Number of calls waiting for a free slot before new ones are rejected.
+    pub max_queued: usize,
+    /// How long a call waits for a free slot before giving up.
+    pub queue_timeout_millis: u64,
+    /// Number of times a failed read from Kreta is retried, token creation
+    /// is never retried.
+    pub max_retries: u32,
+    pub retry_base_delay_millis: u64,
+    pub retry_max_delay_millis: u64,
+    /// Number of failures in a row opening the circuit of an institute,
+    /// 0 disables the circuit breaker.
+    pub circuit_failure_threshold: u32,
+    /// How long an open circuit fails fast before a probe call is let through.
+    pub circuit_open_secs: u64,
+    /// Whether institute codes are checked against the list of the global api.
+    pub verify_institutes: bool,
+    pub institute_list_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    pub schedule_ttl_secs: u64,
+    pub profile_ttl_secs: u64,
+    pub tasks_ttl_secs: u64,
+    pub homework_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub min_size: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Filter in `env_logger` syntax, overridden by `RUST_LOG` when it is set.
+    pub level: String,
+    /// Whether to write structured JSON lines instead of plain text.
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub enabled: bool,
+    /// Allowed origins, any origin is allowed when empty.
+    pub allowed_origins: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Whether `/readyz` checks that the global Kreta api is reachable.
+    pub probe_upstream: bool,
+    pub probe_timeout_millis: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreConfig {
+    /// Whether the data of students is saved to serve it while Kreta is down.
+    pub enabled: bool,
+    /// Path of the SQLite database file.
+    pub path: String,
+    /// Days the changes detected for `/changes` are kept.
+    pub changes_retention_days: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionsConfig {
+    /// Whether clients can subscribe to webhook notifications, needs the store.
+    pub enabled: bool,
+    /// Seconds between two checks of every subscription for changes.
+    pub poll_interval_secs: u64,
+    pub webhook_timeout_secs: u64,
+    /// Number of subscriptions checked and delivered at once, so a slow
+    /// webhook doesn't hold up the others.
+    pub max_concurrent_polls: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Seconds between two checks for changes of a `/events/stream` session.
+    pub refresh_interval_secs: u64,
+    /// Seconds between two heartbeat comments, keeping idle streams open
+    /// through proxies.
+    pub heartbeat_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Whether the client ip is taken from the last `X-Forwarded-For` entry,
+    /// only enable it behind a reverse proxy which appends to the header.
+    pub trust_forwarded_for: bool,
+    pub default: RateLimitRule,
+    /// Rules for specific routes, like `/token`, overriding the default one.
+    pub routes: HashMap<String, RateLimitRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    /// Number of requests a client can make at once.
+    pub burst: u32,
+    /// Number of requests a client regains every minute.
+    pub per_minute: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: String::from("127.0.0.1"),
+            port: 9110,
+            workers: None,
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            institute_url: String::from("https://{institute}.e-kreta.hu"),
+            global_api_url: String::from("https://kretaglobalmobileapi.ekreta.hu"),
+            global_api_key: String::from("7856d350-1fda-45f5-822d-e1a2f3f1acf0"),
+            connect_timeout_secs: 5,
+            timeout_secs: 30,
+            max_concurrent: 64,
+            max_concurrent_per_institute: 8,
+            max_queued: 512,
+            queue_timeout_millis: 10_000,
+            max_retries: 2,
+            retry_base_delay_millis: 200,
+            retry_max_delay_millis: 5_000,
+            circuit_failure_threshold: 5,
+            circuit_open_secs: 30,
+            verify_institutes: false,
+            institute_list_ttl_secs: 3600,
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            max_entries: 10_000,
+            schedule_ttl_secs: 300,
+            profile_ttl_secs: 120,
+            tasks_ttl_secs: 600,
+            homework_ttl_secs: 600,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: String::from("info"),
+            json: true,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            enabled: false,
+            allowed_origins: Vec::new(),
+            max_age_secs: 3600,
+        }
+    }
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probe_upstream: true,
+            probe_timeout_millis: 2000,
+        }
+    }
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            enabled: false,
+            path: String::from("kreta-proxy.sqlite"),
+            changes_retention_days: 30,
+        }
+    }
+}
+
+impl Default for SubscriptionsConfig {
+    fn default() -> Self {
+        SubscriptionsConfig {
+            enabled: false,
+            poll_interval_secs: 900,
+            webhook_timeout_secs: 10,
+            max_concurrent_polls: 8,
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            refresh_interval_secs: 60,
+            heartbeat_secs: 15,
+        }
+    }
+}
+
+impl Default for RateLimitRule {
+    fn default() -> Self {
+        RateLimitRule {
+            burst: 30,
+            per_minute: 60,
+        }
+    }
+}
+
+impl RateLimitRule {
+    pub fn per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+impl HealthConfig {
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.probe_timeout_millis)
+    }
+}
+
+impl SubscriptionsConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval_secs)
+    }
+
+    pub fn webhook_timeout(&self) -> Duration {
+        Duration::from_secs(self.webhook_timeout_secs)
+    }
+}
+
+impl EventsConfig {
+    pub fn refresh_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_interval_secs)
+    }
+
+    pub fn heartbeat(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_secs)
+    }
+}
+
+impl UpstreamConfig {
+    pub fn institute_url(&self, institute: &str) -> String {
+        self.institute_url.replace(INSTITUTE_PLACEHOLDER, institute)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_millis)
+    }
+
+    pub fn retry_base_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_base_delay_millis)
+    }
+
+    pub fn retry_max_delay(&self) -> Duration {
+        Duration::from_millis(self.retry_max_delay_millis)
+    }
+
+    pub fn circuit_open_duration(&self) -> Duration {
+        Duration::from_secs(self.circuit_open_secs)
+    }
+
+    pub fn institute_list_ttl(&self) -> Duration {
+        Duration::from_secs(self.institute_list_ttl_secs)
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the file given with `--config <path>` or
+    /// the `KRETA_PROXY_CONFIG` environment variable, applies the environment
+    /// variable overrides and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match config_path() {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_string(), err))?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_string(), err))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(address) = env_var("SERVER_ADDRESS")? {
+            self.server.address = address;
+        }
+        if let Some(port) = env_var("SERVER_PORT")? {
+            self.server.port = port;
+        }
+        if let Some(workers) = env_var("SERVER_WORKERS")? {
+            self.server.workers = Some(workers);
+        }
+        if let Some(timeout) = env_var("UPSTREAM_TIMEOUT_SECS")? {
+            self.upstream.timeout_secs = timeout;
+        }
+        if let Some(max_retries) = env_var("UPSTREAM_MAX_RETRIES")? {
+            self.upstream.max_retries = max_retries;
+        }
+        if let Some(enabled) = env_var("CACHE_ENABLED")? {
+            self.cache.enabled = enabled;
+        }
+        if let Some(min_size) = env_var("COMPRESSION_MIN_SIZE")? {
+            self.compression.min_size = min_size;
+        }
+        if let Some(level) = env_var("LOG_LEVEL")? {
+            self.log.level = level;
+        }
+        if let Some(json) = env_var("LOG_JSON")? {
+            self.log.json = json;
+        }
+        if let Some(enabled) = env_var("STORE_ENABLED")? {
+            self.store.enabled = enabled;
+        }
+        if let Some(path) = env_var("STORE_PATH")? {
+            self.store.path = path;
+        }
+        if let Some(enabled) = env_var("SUBSCRIPTIONS_ENABLED")? {
+            self.subscriptions.enabled = enabled;
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+
+        if (self.server.address.as_str(), self.server.port)
+            .to_socket_addrs()
+            .is_err()
+        {
+            return invalid(format!(
+                "server address {}:{} isn't a valid socket address",
+                self.server.address, self.server.port
+            ));
+        }
+        if self.server.workers == Some(0) {
+            return invalid(String::from("server.workers must be at least 1"));
+        }
+        if !self.upstream.institute_url.contains(INSTITUTE_PLACEHOLDER) {
+            return invalid(format!(
+                "upstream.institute_url must contain {}",
+                INSTITUTE_PLACEHOLDER
+            ));
+        }
+        for url in &[&self.upstream.institute_url, &self.upstream.global_api_url] {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                return invalid(format!("upstream url {} must be http(s)", url));
+            }
+        }
+        if self.upstream.connect_timeout_secs == 0 || self.upstream.timeout_secs == 0 {
+            return invalid(String::from("upstream timeouts must be positive"));
+        }
+        if self.upstream.max_concurrent == 0
+            || self.upstream.max_concurrent_per_institute == 0
+            || self.upstream.max_queued == 0
+            || self.upstream.queue_timeout_millis == 0
+        {
+            return invalid(String::from(
+                "upstream concurrency limits and queue timeout must be positive",
+            ));
+        }
+        if self.upstream.max_retries > 0
+            && (self.upstream.retry_base_delay_millis == 0
+                || self.upstream.retry_base_delay_millis > self.upstream.retry_max_delay_millis)
+        {
+            return invalid(String::from(
+                "upstream.retry_base_delay_millis must be positive and at most retry_max_delay_millis",
+            ));
+        }
+        if self.upstream.circuit_failure_threshold > 0 && self.upstream.circuit_open_secs == 0 {
+            return invalid(String::from("upstream.circuit_open_secs must be positive"));
+        }
+        if self.upstream.institute_list_ttl_secs == 0 {
+            return invalid(String::from(
+                "upstream.institute_list_ttl_secs must be positive",
+            ));
+        }
+        if self.cache.enabled
+            && (self.cache.max_entries == 0
+                || self.cache.schedule_ttl_secs == 0
+                || self.cache.profile_ttl_secs == 0
+                || self.cache.tasks_ttl_secs == 0
+                || self.cache.homework_ttl_secs == 0)
+        {
+            return invalid(String::from(
+                "cache.max_entries and the cache ttls must be positive when the cache is enabled",
+            ));
+        }
+        if !is_valid_log_level(&self.log.level) {
+            return invalid(format!("log level {} isn't valid", self.log.level));
+        }
+        if self.health.probe_upstream && self.health.probe_timeout_millis == 0 {
+            return invalid(String::from("health.probe_timeout_millis must be positive"));
+        }
+        let rate_limit_rules =
+            std::iter::once(&self.rate_limit.default).chain(self.rate_limit.routes.values());
+        for rule in rate_limit_rules {
+            if rule.burst == 0 || rule.per_minute == 0 {
+                return invalid(String::from(
+                    "rate_limit burst and per_minute must be positive",
+                ));
+            }
+        }
+        if self.cors.enabled && self.cors.allowed_origins.iter().any(|o| o.is_empty()) {
+            return invalid(String::from(
+                "cors.allowed_origins can't contain empty origins",
+            ));
+        }
+        if self.store.enabled && self.store.path.trim().is_empty() {
+            return invalid(String::from("store.path can't be empty"));
+        }
+        if self.store.changes_retention_days == 0 {
+            return invalid(String::from(
+                "store.changes_retention_days must be positive",
+            ));
+        }
+        if self.subscriptions.enabled && !self.store.enabled {
+            return invalid(String::from("subscriptions need the store to be enabled"));
+        }
+        if self.subscriptions.poll_interval_secs == 0
+            || self.subscriptions.webhook_timeout_secs == 0
+            || self.subscriptions.max_concurrent_polls == 0
+        {
+            return invalid(String::from(
+                "subscriptions.poll_interval_secs, webhook_timeout_secs and max_concurrent_polls must be positive",
+            ));
+        }
+        if self.events.refresh_interval_secs == 0 || self.events.heartbeat_secs == 0 {
+            return invalid(String::from(
+                "events.refresh_interval_secs and heartbeat_secs must be positive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Makes the configuration available to the rest of the proxy through `get`.
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/// Returns the configuration the server was started with,
+/// or the defaults when it hasn't been initialized (e.g. in tests).
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Whether `filter` is made of `env_logger` directives: a level, a module
+/// path, or a module path with a level after `=`, optionally followed by a
+/// `/` and a message filter.
+fn is_valid_log_level(filter: &str) -> bool {
+    let directives = filter.split('/').next().unwrap_or("");
+    let is_module_path = |module: &str| {
+        !module.is_empty()
+            && module
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    };
+
+    !directives.trim().is_empty()
+        && directives
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+            .all(|directive| {
+                let mut parts = directive.splitn(2, '=');
+                let module = parts.next().unwrap_or("");
+                match parts.next() {
+                    Some(level) => {
+                        is_module_path(module)
+                            && (level.is_empty() || LevelFilter::from_str(level).is_ok())
+                    }
+                    None => LevelFilter::from_str(module).is_ok() || is_module_path(module),
+                }
+            })
+}
+
+fn config_path() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            return args.next();
+        }
+        if arg.starts_with("--config=") {
+            return Some(arg["--config=".len()..].to_string());
+        }
+    }
+    std::env::var(CONFIG_PATH_ENV).ok()
+}
+
+fn env_var<T: FromStr>(name: &'static str) -> Result<Option<T>, ConfigError> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Environment(name, value)),
+        Err(_err) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse() {
+        let config: Config = toml::from_str(
+            r#"
+            [server]
+            address = "0.0.0.0"
+            port = 8080
+            workers = 4
+
+            [upstream]
+            institute_url = "http://localhost:9999/{institute}"
+
+            [cors]
+            enabled = true
+            allowed_origins = ["https://hazizz.hu"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.port, 8080);
+        assert_eq!(config.server.workers, Some(4));
+        assert_eq!(
+            config.upstream.institute_url("klik0000"),
+            "http://localhost:9999/klik0000"
+        );
+        assert_eq!(config.upstream.timeout_secs, 30);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let mut config = Config::default();
+        config.upstream.institute_url = String::from("https://e-kreta.hu");
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.log.level = String::from("kreta_proxy=verbose");
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.server.workers = Some(0);
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("[server]\nprot = 1").is_err());
+    }
+
+    #[test]
+    fn test_log_levels() {
+        for level in &[
+            "info",
+            "kreta_proxy",
+            "kreta_proxy,actix_web=warn",
+            "warn,kreta_proxy::requests=debug",
+            "kreta_proxy=",
+            "debug/upstream",
+        ] {
+            assert!(is_valid_log_level(level), "{}", level);
+        }
+        for level in &[
+            "",
+            "kreta_proxy=verbose",
+            "=debug",
+            "kreta proxy",
+            "a=info=debug",
+        ] {
+            assert!(!is_valid_log_level(level), "{}", level);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use actix_web::web;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::messages::Language;
+
+/// Header carrying the correlation id of a request, both from the clients
+/// and towards Kreta.
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+
+const REQUEST_ID_MAX_LENGTH: usize = 128;
+const GENERATED_REQUEST_ID_LENGTH: usize = 16;
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// Information about the request being handled, available to everything
+/// running inside its task, like logging and upstream calls.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub id: String,
+    pub institute: Option<String>,
+    /// Language of the error messages, from the `Accept-Language` header.
+    pub language: Language,
+    /// Whether the client asked for fresh data with `?refresh=true`.
+    pub refresh: bool,
+    /// Time spent waiting for Kreta while handling the request, in microseconds.
+    pub upstream_micros: Arc<AtomicU64>,
+    /// Number of calls to Kreta retried while handling the request.
+    pub upstream_retries: Arc<AtomicU32>,
+}
+
+#[derive(Deserialize)]
+struct ContextQuery {
+    url: Option<String>,
+    #[serde(default)]
+    refresh: bool,
+}
+
+impl RequestContext {
+    /// Uses the `X-Request-Id` of the client when it is sensible, otherwise
+    /// generates a new one.
+    pub fn from_request(req: &ServiceRequest) -> RequestContext {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(String::from)
+            .unwrap_or_else(generate_request_id);
+
+        let (institute, refresh) = web::Query::<ContextQuery>::from_query(req.query_string())
+            .map(|query| {
+                let query = query.into_inner();
+                (query.url, query.refresh)
+            })
+            .unwrap_or((None, false));
+
+        let language = Language::from_accept_language(
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|accept_language| accept_language.to_str().ok()),
+        );
+
+        RequestContext {
+            id,
+            institute,
+            language,
+            refresh,
+            upstream_micros: Arc::new(AtomicU64::new(0)),
+            upstream_retries: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub fn upstream_time(&self) -> Duration {
+        Duration::from_micros(self.upstream_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn upstream_retries(&self) -> u32 {
+        self.upstream_retries.load(Ordering::Relaxed)
+    }
+}
+
+/// Runs `f` with `context` as the context of the current request.
+pub async fn scope<F: Future>(context: RequestContext, f: F) -> F::Output {
+    REQUEST.scope(context, f).await
+}
+
+/// The context of the current request, to carry it over to tasks spawned
+/// for the request.
+pub fn current() -> Option<RequestContext> {
+    REQUEST.try_with(RequestContext::clone).ok()
+}
+
+pub fn request_id() -> Option<String> {
+    REQUEST.try_with(|context| context.id.clone()).ok()
+}
+
+pub fn institute() -> Option<String> {
+    REQUEST
+        .try_with(|context| context.institute.clone())
+        .ok()
+        .flatten()
+}
+
+/// Language of the current request, English outside of requests.
+pub fn language() -> Language {
+    REQUEST
+        .try_with(|context| context.language)
+        .unwrap_or(Language::English)
+}
+
+pub fn refresh() -> bool {
+    REQUEST.try_with(|context| context.refresh).unwrap_or(false)
+}
+
+/// Adds `elapsed` to the time the current request spent waiting for Kreta.
+pub fn add_upstream_time(elapsed: Duration) {
+    let _ = REQUEST.try_with(|context| {
+        context
+            .upstream_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed)
+    });
+}
+
+pub fn add_upstream_retry() {
+    let _ = REQUEST.try_with(|context| context.upstream_retries.fetch_add(1, Ordering::Relaxed));
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= REQUEST_ID_MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn generate_request_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_REQUEST_ID_LENGTH)
+        .collect()
+}
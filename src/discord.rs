@@ -0,0 +1,394 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::StatusCode;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::subscriptions::{Event, EventKind};
+use crate::upstream;
+
+/// Discord accepts at most this many embeds in a message.
+const MAX_EMBEDS: usize = 10;
+/// Discord accepts at most this many characters in the embeds of a message.
+const MAX_MESSAGE_LENGTH: usize = 6000;
+/// Longest title Discord accepts.
+const MAX_TITLE_LENGTH: usize = 256;
+/// Longest description kept, well below the limit of Discord.
+const MAX_DESCRIPTION_LENGTH: usize = 2000;
+/// Longest field value Discord accepts.
+const MAX_FIELD_LENGTH: usize = 1024;
+
+/// How many times a message is posted while Discord rate limits the webhook.
+const MAX_ATTEMPTS: u32 = 3;
+/// Wait when Discord rate limits without a `Retry-After` header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Longer waits are left for the next poll.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+const GREEN: u32 = 3_066_993;
+const BLUE: u32 = 3_447_003;
+const RED: u32 = 15_158_332;
+const ORANGE: u32 = 15_105_570;
+const PURPLE: u32 = 10_181_046;
+
+static DISCORD_HOSTS: &[&str] = &[
+    "discord.com",
+    "discordapp.com",
+    "ptb.discord.com",
+    "canary.discord.com",
+];
+
+/// Whether `webhook_url` is a Discord webhook, which is sent embeds instead
+/// of signed events.
+pub fn is_discord_webhook(webhook_url: &str) -> bool {
+    reqwest::Url::parse(webhook_url)
+        .map(|url| {
+            url.host_str()
+                .map_or(false, |host| DISCORD_HOSTS.contains(&host))
+                && url.path().starts_with("/api/webhooks/")
+        })
+        .unwrap_or(false)
+}
+
+#[derive(Serialize, Debug)]
+struct DiscordMessage<'a> {
+    username: &'static str,
+    embeds: &'a [Embed],
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Embed {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    color: u32,
+    fields: Vec<EmbedField>,
+    timestamp: DateTime<Utc>,
+}
+
+impl Embed {
+    /// The characters counted against the limit of a message, at most about
+    /// 4500 thanks to the truncated title, description and fields.
+    fn length(&self) -> usize {
+        self.title.chars().count()
+            + self
+                .description
+                .as_ref()
+                .map_or(0, |description| description.chars().count())
+            + self
+                .fields
+                .iter()
+                .map(|field| field.name.chars().count() + field.value.chars().count())
+                .sum::<usize>()
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EmbedField {
+    name: &'static str,
+    value: String,
+    inline: bool,
+}
+
+fn field(name: &'static str, value: String) -> EmbedField {
+    EmbedField {
+        name,
+        value: truncate(value, MAX_FIELD_LENGTH),
+        inline: true,
+    }
+}
+
+/// A field of an item as text, `-` when it is missing or empty like in the
+/// refined Kreta data.
+fn text(item: &Value, name: &str) -> String {
+    match item.get(name) {
+        Some(Value::String(text)) if !text.is_empty() => text.clone(),
+        Some(Value::Number(number)) => number.to_string(),
+        _ => String::from("-"),
+    }
+}
+
+fn truncate(text: String, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text;
+    }
+    let mut text: String = text.chars().take(max_length - 1).collect();
+    text.push('…');
+    text
+}
+
+/// The embed of an event, in Hungarian like the calendars.
+pub fn embed(event: &Event) -> Embed {
+    let item = &event.item;
+    let subject = text(item, "subject");
+    let (title, description, color, fields) = match event.kind {
+        EventKind::NewGrade => (
+            format!("Új jegy: {}", subject),
+            None,
+            GREEN,
+            vec![
+                field("Jegy", text(item, "grade")),
+                field("Típus", text(item, "gradeType")),
+                field("Súly", format!("{}%", text(item, "weight"))),
+                field("Téma", text(item, "topic")),
+            ],
+        ),
+        EventKind::NewNote => (
+            text(item, "title"),
+            Some(truncate(text(item, "content"), MAX_DESCRIPTION_LENGTH)),
+            BLUE,
+            vec![
+                field("Tanár", text(item, "teacher")),
+                field("Típus", text(item, "type")),
+            ],
+        ),
+        EventKind::CancelledLesson => (
+            format!("Elmarad: {}", subject),
+            None,
+            RED,
+            vec![
+                field("Dátum", text(item, "date")),
+                field("Óra", text(item, "periodNumber")),
+                field("Tanár", text(item, "teacher")),
+            ],
+        ),
+        EventKind::Substitution => (
+            format!("Helyettesítés: {}", subject),
+            None,
+            ORANGE,
+            vec![
+                field("Dátum", text(item, "date")),
+                field("Óra", text(item, "periodNumber")),
+                field("Helyettesítő tanár", text(item, "teacher")),
+                field("Terem", text(item, "room")),
+            ],
+        ),
+        EventKind::NewTask => (
+            format!("Bejelentett számonkérés: {}", subject),
+            None,
+            PURPLE,
+            vec![
+                field("Dátum", text(item, "dueDate")),
+                field("Téma", text(item, "topic")),
+                field("Számonkérés módja", text(item, "gradeType")),
+                field("Tanár", text(item, "teacher")),
+            ],
+        ),
+    };
+
+    Embed {
+        title: truncate(title, MAX_TITLE_LENGTH),
+        description,
+        color,
+        fields,
+        timestamp: event.detected_at,
+    }
+}
+
+/// Splits the events into the messages Discord accepts, each with at most
+/// `MAX_EMBEDS` embeds of `MAX_MESSAGE_LENGTH` characters in total.
+pub fn batches(events: &[Event]) -> Vec<&[Event]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    let mut length = 0;
+    for (index, event) in events.iter().enumerate() {
+        let event_length = embed(event).length();
+        let full = index - start == MAX_EMBEDS || length + event_length > MAX_MESSAGE_LENGTH;
+        if index > start && full {
+            batches.push(&events[start..index]);
+            start = index;
+            length = 0;
+        }
+        length += event_length;
+    }
+    if start < events.len() {
+        batches.push(&events[start..]);
+    }
+    batches
+}
+
+/// Posts a batch of events to a Discord webhook as one message. When the
+/// webhook is rate limited the message is posted again after the wait
+/// Discord asks for.
+pub async fn deliver(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    events: &[Event],
+) -> Result<(), String> {
+    let embeds: Vec<Embed> = events.iter().map(embed).collect();
+    let mut attempt = 1;
+    loop {
+        let response = client
+            .post(webhook_url)
+            .json(&DiscordMessage {
+                username: "Kreta proxy",
+                embeds: &embeds,
+            })
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        if status != StatusCode::TOO_MANY_REQUESTS || attempt == MAX_ATTEMPTS {
+            return Err(format!("Discord answered {}", status));
+        }
+        let retry_after = upstream::retry_after(response.headers()).unwrap_or(DEFAULT_RETRY_AFTER);
+        if retry_after > MAX_RETRY_AFTER {
+            return Err(format!(
+                "Discord asked to retry after {} seconds",
+                retry_after.as_secs()
+            ));
+        }
+        tokio::time::delay_for(retry_after).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod discord_test {
+    use super::*;
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn grade_event(id: i64) -> Event {
+        Event {
+            id,
+            kind: EventKind::NewGrade,
+            detected_at: Utc::now(),
+            item: json!({
+                "subject": "Matematika",
+                "gradeType": "Témazáró",
+                "grade": "Jeles(5)",
+                "weight": 200,
+                "topic": "",
+            }),
+        }
+    }
+
+    fn note_event(id: i64) -> Event {
+        Event {
+            id,
+            kind: EventKind::NewNote,
+            detected_at: Utc::now(),
+            item: json!({
+                "title": "Osztálykirándulás",
+                "content": "x".repeat(3000),
+                "teacher": "Kiss Anna",
+                "type": "Tájékoztatás",
+            }),
+        }
+    }
+
+    #[test]
+    fn test_discord_webhooks() {
+        assert!(is_discord_webhook(
+            "https://discord.com/api/webhooks/1234/s3cr3t"
+        ));
+        assert!(is_discord_webhook(
+            "https://discordapp.com/api/webhooks/1234/s3cr3t"
+        ));
+        assert!(!is_discord_webhook("https://example.com/api/webhooks/1234"));
+        assert!(!is_discord_webhook("https://discord.com/channels/1234"));
+    }
+
+    #[test]
+    fn test_grade_embed() {
+        let embed = embed(&grade_event(1));
+        assert_eq!(embed.title, "Új jegy: Matematika");
+        assert_eq!(embed.color, GREEN);
+        assert_eq!(embed.fields[0], field("Jegy", String::from("Jeles(5)")));
+        assert_eq!(embed.fields[2], field("Súly", String::from("200%")));
+        assert_eq!(embed.fields[3], field("Téma", String::from("-")));
+    }
+
+    #[test]
+    fn test_batches() {
+        let grades: Vec<Event> = (1..=12).map(grade_event).collect();
+        let sizes: Vec<usize> = batches(&grades).iter().map(|batch| batch.len()).collect();
+        assert_eq!(sizes, vec![MAX_EMBEDS, 2]);
+
+        // Long notes fill a message before the embed limit
+        let notes: Vec<Event> = (1..=5).map(note_event).collect();
+        let sizes: Vec<usize> = batches(&notes).iter().map(|batch| batch.len()).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+        for batch in batches(&notes) {
+            let length: usize = batch.iter().map(|event| embed(event).length()).sum();
+            assert!(length <= MAX_MESSAGE_LENGTH);
+        }
+
+        assert!(batches(&[]).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_deliver_to_stand_in() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let stand_in = {
+            let received = received.clone();
+            test::start(move || {
+                let received = received.clone();
+                App::new().route(
+                    "/api/webhooks/1234/s3cr3t",
+                    web::post().to(move |body: web::Json<Value>| {
+                        received.lock().unwrap().push(body.into_inner());
+                        async { HttpResponse::NoContent().finish() }
+                    }),
+                )
+            })
+        };
+
+        let events: Vec<Event> = (1..=3).map(grade_event).collect();
+        let client = reqwest::Client::new();
+        deliver(&client, &stand_in.url("/api/webhooks/1234/s3cr3t"), &events)
+            .await
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["embeds"].as_array().unwrap().len(), 3);
+        assert_eq!(received[0]["username"], "Kreta proxy");
+
+        assert!(deliver(&client, &stand_in.url("/unknown"), &events)
+            .await
+            .is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_deliver_when_rate_limited() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let stand_in = {
+            let attempts = attempts.clone();
+            test::start(move || {
+                let attempts = attempts.clone();
+                App::new().route(
+                    "/api/webhooks/1234/s3cr3t",
+                    web::post().to(move || {
+                        let response = if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                            HttpResponse::TooManyRequests()
+                                .header("Retry-After", "0")
+                                .finish()
+                        } else {
+                            HttpResponse::NoContent().finish()
+                        };
+                        async { response }
+                    }),
+                )
+            })
+        };
+
+        let client = reqwest::Client::new();
+        deliver(
+            &client,
+            &stand_in.url("/api/webhooks/1234/s3cr3t"),
+            &[grade_event(1)],
+        )
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
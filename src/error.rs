@@ -1,9 +1,16 @@
+use crate::context;
+use crate::messages::{self, RETRY_AFTER_PLACEHOLDER};
+use crate::metrics;
 use crate::resources::KretaErrorResponse;
-use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
 use chrono::Utc;
 use derive_more::Display;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Bodies of failed Kreta answers are kept up to this many bytes.
+const MAX_BODY_LENGTH: usize = 512;
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -11,69 +18,361 @@ pub struct ErrorResponse {
     error_code: u32,
     title: String,
     message: String,
+    /// Technical details of the failure, never translated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
     time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
 impl ErrorResponse {
-    pub fn from_message(error_code: u32, title: String, message: String) -> ErrorResponse {
+    /// Builds the response of the error with `error_code` in the language
+    /// the client asked for.
+    pub fn localized(
+        error_code: u32,
+        retry_after_secs: Option<u64>,
+        detail: Option<String>,
+    ) -> ErrorResponse {
+        let (title, message) = messages::localize(error_code, context::language());
+        let message = match retry_after_secs {
+            Some(retry_after_secs) => {
+                message.replace(RETRY_AFTER_PLACEHOLDER, &retry_after_secs.to_string())
+            }
+            None => message.to_string(),
+        };
         ErrorResponse {
             error_code,
-            title,
+            title: title.to_string(),
             message,
+            detail,
             time: Utc::now().to_rfc3339(),
+            request_id: context::request_id(),
+        }
+    }
+}
+
+/// What Kreta answered when it couldn't be understood, so a maintenance page
+/// can be told apart from an auth failure. The path never has a query string.
+#[derive(Debug)]
+pub struct UpstreamDetails {
+    pub status: u16,
+    pub path: String,
+    pub body: String,
+}
+
+impl UpstreamDetails {
+    pub fn new(status: u16, path: String, body: &str) -> UpstreamDetails {
+        let mut end = body.len().min(MAX_BODY_LENGTH);
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        UpstreamDetails {
+            status,
+            path,
+            body: body[..end].to_string(),
         }
     }
 }
 
+impl fmt::Display for UpstreamDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "status={};path={};body={}",
+            self.status, self.path, self.body
+        )
+    }
+}
+
+/// Errors of the proxy. Every variant answers with its own stable error code:
+///
+/// | Code | Status  | Variant                  | Meaning                                   |
+/// |------|---------|--------------------------|-------------------------------------------|
+/// | 20   | 502     | `KretaRequestSendFailed` | Kreta couldn't be reached                 |
+/// | 21   | 502     | `KretaBadResponse`       | Kreta answered with an unknown error body |
+/// | 22   | 502     | `ParseError`             | Kreta answered with an unexpected body    |
+/// | 23   | 404     | `FeedNotFound`           | The calendar feed token is unknown        |
+/// | 24   | 500     | `SerializationError`     | The response couldn't be serialized       |
+/// | 25   | 429     | `RateLimited`            | The client sent too many requests         |
+/// | 26   | 503     | `UpstreamQueueFull`      | Too many calls are waiting for Kreta      |
+/// | 27   | 503     | `UpstreamQueueTimeout`   | A call waited too long for Kreta          |
+/// | 28   | 503     | `CircuitOpen`            | Kreta of the institute keeps failing      |
+/// | 29   | 400/502 | `ErrorResponse`          | Kreta answered with an error              |
+/// | 30   | 504     | `KretaRequestSendFailed` | Kreta didn't answer in time               |
+/// | 31   | 401     | `ErrorResponse`          | Kreta rejected the access token           |
+/// | 32   | 401     | `InvalidGrant`           | Wrong credentials or refresh token        |
+/// | 33   | 400     | `InvalidInstitute`       | The institute code is invalid or unknown  |
+/// | 34   | 404     | `StoreDisabled`          | The student store isn't enabled           |
+/// | 35   | 500     | `StoreFailed`            | The student store couldn't be used        |
+/// | 36   | 404     | `SubscriptionsDisabled`  | Webhook subscriptions aren't enabled      |
+/// | 37   | 404     | `SubscriptionNotFound`   | The subscription id is unknown            |
+/// | 38   | 400     | `InvalidWebhookUrl`      | The webhook url isn't a public https url  |
+///
+/// Code 31 is also used for `KretaBadResponse` when Kreta answered with 401 or 403.
 #[derive(Debug, Display)]
 pub enum KretaError {
+    /// An error answer of Kreta which isn't a `KretaErrorResponse`.
     #[display(fmt = "Kreta invalid response!")]
-    KretaBadResponse(reqwest::Error),
+    KretaBadResponse(UpstreamDetails),
     #[display(fmt = "Invalid access token!")]
     KretaRequestSendFailed(reqwest::Error),
+    /// A successful answer of Kreta which couldn't be parsed, with the reason.
     #[display(fmt = "Response couldn't be parsed!")]
-    ParseError(reqwest::Error),
+    ParseError(UpstreamDetails, String),
+    /// An error answer of Kreta with its status code.
     #[display(fmt = "Kreta responses with error!")]
-    ErrorResponse(KretaErrorResponse),
+    ErrorResponse(u16, KretaErrorResponse),
+    /// Kreta refused the credentials or the refresh token at login.
+    #[display(fmt = "Invalid credentials!")]
+    InvalidGrant(KretaErrorResponse),
+    #[display(fmt = "Calendar feed not found!")]
+    FeedNotFound,
+    #[display(fmt = "Response couldn't be serialized!")]
+    SerializationError(String),
+    #[display(fmt = "Too many requests!")]
+    RateLimited(u64),
+    #[display(fmt = "Too many requests are waiting for Kreta!")]
+    UpstreamQueueFull,
+    #[display(fmt = "Timed out waiting for Kreta!")]
+    UpstreamQueueTimeout,
+    #[display(fmt = "Kreta of the institute is unavailable!")]
+    CircuitOpen(u64),
+    /// The `url` parameter isn't a valid or known institute code.
+    #[display(fmt = "Invalid institute!")]
+    InvalidInstitute(String),
+    #[display(fmt = "Student store is disabled!")]
+    StoreDisabled,
+    #[display(fmt = "Student store failed!")]
+    StoreFailed(String),
+    #[display(fmt = "Subscriptions are disabled!")]
+    SubscriptionsDisabled,
+    #[display(fmt = "Subscription not found!")]
+    SubscriptionNotFound,
+    #[display(fmt = "Invalid webhook url!")]
+    InvalidWebhookUrl(String),
 }
 
-impl actix_web::error::ResponseError for KretaError {
-    fn error_response(&self) -> HttpResponse {
+impl KretaError {
+    pub fn name(&self) -> &'static str {
         match self {
-            KretaError::KretaBadResponse(err) => HttpResponse::build(
-                StatusCode::from_u16(500).unwrap(),
-            )
-            .json(ErrorResponse::from_message(
-                21,
-                String::from("Unrecognisable response from kreta server"),
-                format!("{}", err),
-            )),
+            KretaError::KretaBadResponse(_) => "KretaBadResponse",
+            KretaError::KretaRequestSendFailed(_) => "KretaRequestSendFailed",
+            KretaError::ParseError(_, _) => "ParseError",
+            KretaError::ErrorResponse(_, _) => "ErrorResponse",
+            KretaError::InvalidGrant(_) => "InvalidGrant",
+            KretaError::FeedNotFound => "FeedNotFound",
+            KretaError::SerializationError(_) => "SerializationError",
+            KretaError::RateLimited(_) => "RateLimited",
+            KretaError::UpstreamQueueFull => "UpstreamQueueFull",
+            KretaError::UpstreamQueueTimeout => "UpstreamQueueTimeout",
+            KretaError::CircuitOpen(_) => "CircuitOpen",
+            KretaError::InvalidInstitute(_) => "InvalidInstitute",
+            KretaError::StoreDisabled => "StoreDisabled",
+            KretaError::StoreFailed(_) => "StoreFailed",
+            KretaError::SubscriptionsDisabled => "SubscriptionsDisabled",
+            KretaError::SubscriptionNotFound => "SubscriptionNotFound",
+            KretaError::InvalidWebhookUrl(_) => "InvalidWebhookUrl",
+        }
+    }
 
-            KretaError::KretaRequestSendFailed(err) => HttpResponse::build(
-                StatusCode::from_u16(500).unwrap(),
-            )
-            .json(ErrorResponse::from_message(
-                20,
-                String::from("Request failed"),
-                format!("{}", err),
-            )),
-            KretaError::ParseError(err) => HttpResponse::build(StatusCode::from_u16(500).unwrap())
-                .json(ErrorResponse::from_message(
-                    22,
-                    String::from("Parsing response failed"),
-                    format!("{}", err),
-                )),
-            KretaError::ErrorResponse(response) => HttpResponse::build(
-                StatusCode::from_u16(400).unwrap(),
-            )
-            .json(ErrorResponse::from_message(
-                22,
-                String::from("Kreta error response"),
-                format!(
-                    "error_title={};error_message={};error_code={}",
-                    response.error, response.error_description, response.error_code
-                ),
+    /// The error code of the error table above.
+    pub fn code(&self) -> u32 {
+        match self {
+            KretaError::KretaRequestSendFailed(err) if err.is_timeout() => 30,
+            KretaError::KretaRequestSendFailed(_) => 20,
+            KretaError::KretaBadResponse(details) if is_unauthorized(details.status) => 31,
+            KretaError::KretaBadResponse(_) => 21,
+            KretaError::ParseError(_, _) => 22,
+            KretaError::FeedNotFound => 23,
+            KretaError::SerializationError(_) => 24,
+            KretaError::RateLimited(_) => 25,
+            KretaError::UpstreamQueueFull => 26,
+            KretaError::UpstreamQueueTimeout => 27,
+            KretaError::CircuitOpen(_) => 28,
+            KretaError::ErrorResponse(status, _) if is_unauthorized(*status) => 31,
+            KretaError::ErrorResponse(_, _) => 29,
+            KretaError::InvalidGrant(_) => 32,
+            KretaError::InvalidInstitute(_) => 33,
+            KretaError::StoreDisabled => 34,
+            KretaError::StoreFailed(_) => 35,
+            KretaError::SubscriptionsDisabled => 36,
+            KretaError::SubscriptionNotFound => 37,
+            KretaError::InvalidWebhookUrl(_) => 38,
+        }
+    }
+
+    /// Whether Kreta couldn't be reached or failed on its side, rather than
+    /// rejecting the request.
+    pub fn is_upstream_unavailable(&self) -> bool {
+        match self.status_code() {
+            StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => true,
+            _ => false,
+        }
+    }
+
+    fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            KretaError::RateLimited(retry_after_secs)
+            | KretaError::CircuitOpen(retry_after_secs) => Some(*retry_after_secs),
+            _ => None,
+        }
+    }
+
+    /// Technical details of the error, shown next to the translated message.
+    fn detail(&self) -> Option<String> {
+        match self {
+            KretaError::KretaBadResponse(details) => Some(format!("{}", details)),
+            KretaError::KretaRequestSendFailed(err) => Some(format!("{}", err)),
+            KretaError::ParseError(details, err) => Some(format!("{};error={}", details, err)),
+            KretaError::ErrorResponse(_, response) => Some(format!(
+                "error_title={};error_message={};error_code={}",
+                response.error, response.error_description, response.error_code
             )),
+            KretaError::InvalidGrant(response) => Some(response.error_description.clone()),
+            KretaError::SerializationError(err) | KretaError::StoreFailed(err) => Some(err.clone()),
+            KretaError::InvalidInstitute(institute) => Some(format!("url={}", institute)),
+            KretaError::InvalidWebhookUrl(url) => Some(format!("webhook_url={}", url)),
+            KretaError::FeedNotFound
+            | KretaError::StoreDisabled
+            | KretaError::SubscriptionsDisabled
+            | KretaError::SubscriptionNotFound
+            | KretaError::RateLimited(_)
+            | KretaError::UpstreamQueueFull
+            | KretaError::UpstreamQueueTimeout
+            | KretaError::CircuitOpen(_) => None,
         }
     }
 }
+
+fn is_unauthorized(upstream_status: u16) -> bool {
+    upstream_status == 401 || upstream_status == 403
+}
+
+impl ResponseError for KretaError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            KretaError::KretaRequestSendFailed(err) if err.is_timeout() => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            KretaError::KretaBadResponse(details) if is_unauthorized(details.status) => {
+                StatusCode::UNAUTHORIZED
+            }
+            KretaError::KretaRequestSendFailed(_)
+            | KretaError::KretaBadResponse(_)
+            | KretaError::ParseError(_, _) => StatusCode::BAD_GATEWAY,
+            KretaError::ErrorResponse(status, _) if is_unauthorized(*status) => {
+                StatusCode::UNAUTHORIZED
+            }
+            KretaError::ErrorResponse(status, _) if *status >= 500 => StatusCode::BAD_GATEWAY,
+            KretaError::ErrorResponse(_, _) => StatusCode::BAD_REQUEST,
+            KretaError::InvalidGrant(_) => StatusCode::UNAUTHORIZED,
+            KretaError::InvalidInstitute(_) | KretaError::InvalidWebhookUrl(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            KretaError::FeedNotFound
+            | KretaError::StoreDisabled
+            | KretaError::SubscriptionsDisabled
+            | KretaError::SubscriptionNotFound => StatusCode::NOT_FOUND,
+            KretaError::SerializationError(_) | KretaError::StoreFailed(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            KretaError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            KretaError::UpstreamQueueFull
+            | KretaError::UpstreamQueueTimeout
+            | KretaError::CircuitOpen(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        metrics::count_error(self.name());
+
+        let retry_after_secs = self.retry_after_secs();
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after_secs) = retry_after_secs {
+            response.header(header::RETRY_AFTER, retry_after_secs.to_string());
+        }
+        response
+            .header(header::CONTENT_LANGUAGE, context::language().tag())
+            .header(header::VARY, "Accept-Language");
+        response.json(ErrorResponse::localized(
+            self.code(),
+            retry_after_secs,
+            self.detail(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod error_test {
+    use super::*;
+
+    fn kreta_error(error: &str) -> KretaErrorResponse {
+        KretaErrorResponse {
+            error: String::from(error),
+            error_code: String::from("0"),
+            error_description: String::from("description"),
+        }
+    }
+
+    #[test]
+    fn test_upstream_status_mapping() {
+        let unauthorized = KretaError::ErrorResponse(401, kreta_error("unauthorized"));
+        assert_eq!(unauthorized.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(unauthorized.code(), 31);
+
+        let forbidden = KretaError::ErrorResponse(403, kreta_error("forbidden"));
+        assert_eq!(forbidden.status_code(), StatusCode::UNAUTHORIZED);
+
+        let maintenance = KretaError::ErrorResponse(503, kreta_error("maintenance"));
+        assert_eq!(maintenance.status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(maintenance.code(), 29);
+
+        let bad_request = KretaError::ErrorResponse(400, kreta_error("invalid_request"));
+        assert_eq!(bad_request.status_code(), StatusCode::BAD_REQUEST);
+
+        let invalid_grant = KretaError::InvalidGrant(kreta_error("invalid_grant"));
+        assert_eq!(invalid_grant.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(invalid_grant.code(), 32);
+    }
+
+    #[test]
+    fn test_upstream_details() {
+        let page = "<html>Karbantartás</html>".repeat(100);
+        let details = UpstreamDetails::new(503, String::from("/api/v3/Student"), &page);
+        assert!(details.body.len() <= MAX_BODY_LENGTH);
+        assert!(page.starts_with(&details.body));
+
+        let maintenance = KretaError::KretaBadResponse(details);
+        assert_eq!(maintenance.status_code(), StatusCode::BAD_GATEWAY);
+        assert_eq!(maintenance.code(), 21);
+
+        let unauthorized = KretaError::KretaBadResponse(UpstreamDetails::new(
+            401,
+            String::from("/api/v3/Student"),
+            "",
+        ));
+        assert_eq!(unauthorized.status_code(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            unauthorized.detail().unwrap(),
+            "status=401;path=/api/v3/Student;body="
+        );
+    }
+
+    #[test]
+    fn test_retry_after_header() {
+        let response = KretaError::CircuitOpen(7).error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "7");
+    }
+
+    #[test]
+    fn test_localized_message() {
+        let response = ErrorResponse::localized(25, Some(3), None);
+        assert_eq!(response.title, "Too many requests");
+        assert_eq!(response.message, "Retry after 3 seconds");
+        assert_eq!(response.error_code, 25);
+    }
+}
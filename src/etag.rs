@@ -0,0 +1,154 @@
+use actix_web::dev::{Body, ResponseBody, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::HttpResponse;
+use sha2::{Digest, Sha256};
+
+/// Headers a `304 Not Modified` answer keeps from the full response.
+static NOT_MODIFIED_HEADERS: &[header::HeaderName] = &[
+    header::CACHE_CONTROL,
+    header::VARY,
+    header::CONTENT_LOCATION,
+];
+
+/// Adds an `ETag` made of a hash of the body to successful GET responses,
+/// answering `304 Not Modified` without the body when the client's
+/// `If-None-Match` already names it. The upstream call is still made, but
+/// mobile clients polling for changes don't download the same data again.
+///
+/// Calendars are skipped, their `DTSTAMP` is the time they were rendered so
+/// their body is different every time.
+pub fn conditional_get(
+    method: &Method,
+    if_none_match: Option<HeaderValue>,
+    mut res: ServiceResponse<Body>,
+) -> ServiceResponse<Body> {
+    if (method != Method::GET && method != Method::HEAD) || res.status() != StatusCode::OK {
+        return res;
+    }
+    if is_calendar(&res) {
+        return res;
+    }
+
+    let etag = match res.response().body() {
+        ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => {
+            etag_of(bytes)
+        }
+        _ => return res,
+    };
+
+    if let Some(if_none_match) = if_none_match.as_ref().and_then(|value| value.to_str().ok()) {
+        if matches(if_none_match, &etag) {
+            let mut not_modified = HttpResponse::NotModified();
+            for name in NOT_MODIFIED_HEADERS {
+                if let Some(value) = res.headers().get(name) {
+                    not_modified.header(name.clone(), value.clone());
+                }
+            }
+            return res.into_response(not_modified.header(header::ETAG, etag).finish());
+        }
+    }
+
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        res.headers_mut().insert(header::ETAG, etag);
+    }
+    res
+}
+
+fn is_calendar(res: &ServiceResponse<Body>) -> bool {
+    res.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |content_type| {
+            content_type.starts_with("text/calendar")
+        })
+}
+
+/// A weak entity tag made of the SHA-256 hash of the body. It is weak as the
+/// body is compressed afterwards, which changes the bytes but not what they
+/// mean.
+fn etag_of(body: &[u8]) -> String {
+    let hash: String = Sha256::digest(body)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("W/\"{}\"", hash)
+}
+
+/// Whether an `If-None-Match` header names `etag`, comparing weakly.
+fn matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.trim() == "*"
+        || if_none_match
+            .split(',')
+            .map(|candidate| candidate.trim().trim_start_matches("W/"))
+            .any(|candidate| candidate == etag)
+}
+
+#[cfg(test)]
+mod etag_test {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn response() -> ServiceResponse<Body> {
+        TestRequest::default().to_srv_response(
+            HttpResponse::Ok()
+                .header(header::CACHE_CONTROL, "private, max-age=120")
+                .body("{\"grades\":[]}"),
+        )
+    }
+
+    #[test]
+    fn test_etag_is_added() {
+        let res = conditional_get(&Method::GET, None, response());
+        let etag = res.headers().get(header::ETAG).unwrap().to_str().unwrap();
+        assert_eq!(etag, etag_of(b"{\"grades\":[]}"));
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = conditional_get(&Method::POST, None, response());
+        assert!(res.headers().get(header::ETAG).is_none());
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let etag = etag_of(b"{\"grades\":[]}");
+        assert!(etag.starts_with("W/\""));
+        let if_none_match = HeaderValue::from_str(&format!("\"other\", {}", etag)).unwrap();
+
+        let res = conditional_get(&Method::GET, Some(if_none_match), response());
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            res.headers().get(header::ETAG).unwrap().to_str().unwrap(),
+            etag
+        );
+        assert!(res.headers().get(header::CACHE_CONTROL).is_some());
+
+        let stale = HeaderValue::from_static("\"0000000000000000\"");
+        let res = conditional_get(&Method::GET, Some(stale), response());
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Strong validators of the same body match too
+        let strong = HeaderValue::from_str(etag.trim_start_matches("W/")).unwrap();
+        let res = conditional_get(&Method::GET, Some(strong), response());
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn test_calendars_are_skipped() {
+        let res = TestRequest::default().to_srv_response(
+            HttpResponse::Ok()
+                .content_type("text/calendar; charset=utf-8")
+                .body("BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n"),
+        );
+        let res = conditional_get(&Method::GET, None, res);
+        assert!(res.headers().get(header::ETAG).is_none());
+    }
+
+    #[test]
+    fn test_etag_is_stable() {
+        assert_eq!(
+            etag_of(b"abc"),
+            "W/\"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\""
+        );
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Bytes};
+use actix_web::ResponseError;
+use futures::channel::mpsc;
+use futures::SinkExt;
+use log::warn;
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use crate::changes::{detect, watch, ChangeEvent};
+use crate::config;
+use crate::context;
+use crate::error::{ErrorResponse, KretaError};
+use crate::institute::InstituteCode;
+use crate::requests::refresh_access_token;
+use crate::store::StudentStore;
+
+/// Browsers reconnect this many milliseconds after a stream was closed.
+const RETRY_MILLIS: u64 = 5000;
+/// Frames waiting for a slow client before the stream stops refreshing.
+const BUFFERED_FRAMES: usize = 16;
+
+type Sender = mpsc::Sender<Result<Bytes, KretaError>>;
+
+/// The detections of the students with open streams.
+static DETECTIONS: Lazy<Mutex<HashMap<String, Weak<Detection>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The student of an event stream, with the token its changes are checked with.
+#[derive(Clone)]
+pub struct Session {
+    pub student: String,
+    pub token: String,
+    /// Renews `token` when it expires, streams without it end with the token.
+    pub refresh_token: Option<String>,
+    pub institute: InstituteCode,
+}
+
+/// The changes of a student checked periodically for every stream of the
+/// student, so Kreta is asked once however many streams are open and a single
+/// task renews the token. It lives as long as the streams holding it.
+struct Detection {
+    session: Mutex<Session>,
+    /// Set when the changes can't be checked anymore, ending the streams.
+    failed: watch::Sender<Option<Arc<KretaError>>>,
+    failures: watch::Receiver<Option<Arc<KretaError>>>,
+}
+
+impl Detection {
+    /// The detection of the student of `session`, started if the student has
+    /// no open stream yet.
+    fn join(store: web::Data<StudentStore>, session: Session) -> Arc<Detection> {
+        let mut detections = DETECTIONS.lock().unwrap();
+        if let Some(detection) = detections.get(&session.student).and_then(Weak::upgrade) {
+            detection.offer(session);
+            return detection;
+        }
+
+        detections.retain(|_, detection| detection.upgrade().is_some());
+        let (failed, failures) = watch::channel(None);
+        let student = session.student.clone();
+        let detection = Arc::new(Detection {
+            session: Mutex::new(session),
+            failed,
+            failures,
+        });
+        detections.insert(student, Arc::downgrade(&detection));
+        actix_rt::spawn(keep_detecting(store, Arc::downgrade(&detection)));
+        detection
+    }
+
+    /// Takes the tokens of a newly opened stream, unless only the current ones
+    /// can be renewed.
+    fn offer(&self, session: Session) {
+        let mut current = self.session.lock().unwrap();
+        if session.refresh_token.is_some() || current.refresh_token.is_none() {
+            *current = session;
+        }
+    }
+
+    /// Replaces the rejected access token with one from the refresh token,
+    /// keeping the rotated refresh token. Without a refresh token `err` is
+    /// returned.
+    async fn renew(&self, err: KretaError) -> Result<(), KretaError> {
+        let (institute, refresh_token) = {
+            let session = self.session.lock().unwrap();
+            match &session.refresh_token {
+                Some(refresh_token) => (session.institute.clone(), refresh_token.clone()),
+                None => return Err(err),
+            }
+        };
+        let authentication = refresh_access_token(&institute, &refresh_token).await?;
+        let mut session = self.session.lock().unwrap();
+        session.token = authentication.access_token;
+        session.refresh_token = Some(authentication.refresh_token);
+        Ok(())
+    }
+}
+
+/// Checks the changes of a student every `refresh_interval` until the last
+/// stream of the student is closed, or the token can't be renewed.
+async fn keep_detecting(store: web::Data<StudentStore>, detection: Weak<Detection>) {
+    let refresh_interval = config::get().events.refresh_interval();
+    let mut refresh = time::interval_at(Instant::now() + refresh_interval, refresh_interval);
+
+    loop {
+        refresh.tick().await;
+        let detection = match detection.upgrade() {
+            Some(detection) => detection,
+            None => return,
+        };
+        let session = detection.session.lock().unwrap().clone();
+
+        // Changes found are published to the streams like any other
+        let err = match detect(&store, &session.token, &session.institute).await {
+            Ok(_) => continue,
+            Err(err) if err.status_code() == StatusCode::UNAUTHORIZED => {
+                match detection.renew(err).await {
+                    // The changes are checked with the new token at the next refresh
+                    Ok(()) => continue,
+                    Err(err) => err,
+                }
+            }
+            Err(err) => {
+                warn!("Changes of an event stream couldn't be checked: {}", err);
+                continue;
+            }
+        };
+
+        // Streams opened from now on start a detection of their own
+        let mut detections = DETECTIONS.lock().unwrap();
+        if detections
+            .get(&session.student)
+            .map_or(false, |current| current.ptr_eq(&Arc::downgrade(&detection)))
+        {
+            detections.remove(&session.student);
+        }
+        let _ = detection.failed.broadcast(Some(Arc::new(err)));
+        return;
+    }
+}
+
+/// A Server-Sent Events frame of a change, its id is sent back in
+/// `Last-Event-ID` to resume the stream.
+pub fn change_frame(event: &ChangeEvent) -> Result<Bytes, KretaError> {
+    let data = serde_json::to_string(event)
+        .map_err(|err| KretaError::SerializationError(err.to_string()))?;
+    Ok(Bytes::from(format!(
+        "id: {}\nevent: change\ndata: {}\n\n",
+        event.id, data
+    )))
+}
+
+fn error_frame(err: &KretaError) -> Bytes {
+    let data = serde_json::to_string(&ErrorResponse::localized(err.code(), None, None))
+        .unwrap_or_default();
+    Bytes::from(format!("event: error\ndata: {}\n\n", data))
+}
+
+/// Streams the changes of the student of `session` until the client goes
+/// away: first the ones after `last_event_id`, then every change detected by
+/// the refresh shared by the student's streams, `/changes` or subscriptions,
+/// with heartbeat comments in between.
+pub fn start(
+    store: web::Data<StudentStore>,
+    session: Session,
+    last_event_id: Option<i64>,
+) -> mpsc::Receiver<Result<Bytes, KretaError>> {
+    let (sender, receiver) = mpsc::channel(BUFFERED_FRAMES);
+    // The stream keeps the language and request id of the request opening it
+    let request_context = context::current();
+    actix_rt::spawn(async move {
+        let stream = run(store, session, last_event_id, sender);
+        match request_context {
+            Some(request_context) => context::scope(request_context, stream).await,
+            None => stream.await,
+        }
+    });
+    receiver
+}
+
+async fn run(
+    store: web::Data<StudentStore>,
+    session: Session,
+    last_event_id: Option<i64>,
+    mut sender: Sender,
+) {
+    let events_config = &config::get().events;
+    // Watching before reading the store, so no change falls in between
+    let mut changes = watch();
+    let start = match last_event_id {
+        Some(last_event_id) => Ok(last_event_id),
+        None => store.last_change_id().await,
+    };
+    let mut last_event_id = match start {
+        Ok(last_event_id) => last_event_id,
+        Err(err) => {
+            let _ = sender.send(Ok(error_frame(&err))).await;
+            return;
+        }
+    };
+
+    let retry = Bytes::from(format!("retry: {}\n\n", RETRY_MILLIS));
+    if sender.send(Ok(retry)).await.is_err()
+        || !catch_up(&store, &session, &mut last_event_id, &mut sender).await
+    {
+        return;
+    }
+
+    let mut heartbeat = time::interval_at(
+        Instant::now() + events_config.heartbeat(),
+        events_config.heartbeat(),
+    );
+    // Held until the stream ends, keeping the detection going
+    let detection = Detection::join(store.clone(), session.clone());
+    let mut failures = detection.failures.clone();
+
+    loop {
+        let frame = tokio::select! {
+            _ = heartbeat.tick() => Ok(Bytes::from_static(b": heartbeat\n\n")),
+            failure = failures.recv() => match failure {
+                Some(None) => continue,
+                Some(Some(err)) => {
+                    // The client has to log in again and open a new stream
+                    let _ = sender.send(Ok(error_frame(&err))).await;
+                    return;
+                }
+                None => return,
+            },
+            received = changes.recv() => match received {
+                Ok(change)
+                    if change.student == session.student && change.event.id > last_event_id =>
+                {
+                    last_event_id = change.event.id;
+                    change_frame(&change.event)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(_)) => {
+                    if catch_up(&store, &session, &mut last_event_id, &mut sender).await {
+                        continue;
+                    }
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            }
+        };
+
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(err) => {
+                warn!("Change couldn't be sent on an event stream: {}", err);
+                continue;
+            }
+        };
+        if sender.send(Ok(frame)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends the stored changes of the student after `last_event_id`, returning
+/// whether the client is still listening.
+async fn catch_up(
+    store: &StudentStore,
+    session: &Session,
+    last_event_id: &mut i64,
+    sender: &mut Sender,
+) -> bool {
+    let events = match store.changes_after(&session.student, *last_event_id).await {
+        Ok(events) => events,
+        Err(err) => {
+            warn!("Changes of an event stream couldn't be loaded: {}", err);
+            return true;
+        }
+    };
+
+    for event in events {
+        if let Ok(frame) = change_frame(&event) {
+            if sender.send(Ok(frame)).await.is_err() {
+                return false;
+            }
+        }
+        *last_event_id = event.id;
+    }
+    true
+}
+
+#[cfg(test)]
+mod event_stream_test {
+    use super::*;
+    use crate::changes::{Change, ChangeKind, Watched};
+    use crate::config::StoreConfig;
+    use crate::context::RequestContext;
+    use crate::messages::{localize, Language};
+    use actix_web::test::TestRequest;
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_change_frame() {
+        let event = ChangeEvent {
+            id: 42,
+            detected_at: Utc.timestamp_millis(1_580_754_600_000),
+            change: Change {
+                resource: Watched::Notes,
+                kind: ChangeKind::Added,
+                item: json!({"id": 7}),
+                previous: None,
+            },
+        };
+
+        let frame = change_frame(&event).unwrap();
+        let frame = std::str::from_utf8(&frame).unwrap();
+        assert!(frame.starts_with("id: 42\nevent: change\ndata: {"));
+        assert!(frame.ends_with("}\n\n"));
+        assert_eq!(frame.matches('\n').count(), 4);
+        assert!(frame.contains("\"resource\":\"notes\""));
+    }
+
+    #[actix_rt::test]
+    async fn test_stream_keeps_the_request_context() {
+        let req = TestRequest::default()
+            .header("Accept-Language", "hu")
+            .header("X-Request-Id", "event-stream")
+            .to_srv_request();
+        // Without a store the stream fails right away with an error event
+        let store = web::Data::new(StudentStore::open(&StoreConfig::default()).unwrap());
+        let session = Session {
+            student: String::from("student"),
+            token: String::from("token"),
+            refresh_token: None,
+            institute: InstituteCode::parse("klik035220001").unwrap(),
+        };
+
+        let mut frames = context::scope(RequestContext::from_request(&req), async {
+            start(store, session, None)
+        })
+        .await;
+        let frame = frames.next().await.unwrap().unwrap();
+        let data = std::str::from_utf8(&frame)
+            .unwrap()
+            .trim_start_matches("event: error\ndata: ")
+            .trim_end();
+        let body: Value = serde_json::from_str(data).unwrap();
+
+        assert_eq!(body["title"], localize(34, Language::Hungarian).0);
+        assert_eq!(body["requestId"], "event-stream");
+    }
+
+    #[actix_rt::test]
+    async fn test_streams_of_a_student_share_the_detection() {
+        let store = web::Data::new(StudentStore::open(&StoreConfig::default()).unwrap());
+        let session = |student: &str, refresh_token: Option<&str>| Session {
+            student: student.to_string(),
+            token: String::from("token"),
+            refresh_token: refresh_token.map(String::from),
+            institute: InstituteCode::parse("klik035220001").unwrap(),
+        };
+
+        let first = Detection::join(store.clone(), session("shared", None));
+        let second = Detection::join(store.clone(), session("shared", Some("refresh")));
+        let other = Detection::join(store.clone(), session("other", None));
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        // Tokens which can be renewed aren't replaced by ones which can't
+        Detection::join(store.clone(), session("shared", None));
+        let refresh_token = first.session.lock().unwrap().refresh_token.clone();
+        assert_eq!(refresh_token.as_deref(), Some("refresh"));
+
+        drop((first, second));
+        let third = Detection::join(store, session("shared", None));
+        assert_eq!(third.session.lock().unwrap().refresh_token, None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::web;
+use log::info;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::error::KretaError;
+use crate::institute::InstituteCode;
+use crate::requests::refresh_access_token;
+use crate::store::StudentStore;
+
+const FEED_TOKEN_LENGTH: usize = 40;
+/// A feed is dropped after Kreta rejected its refresh token this many times
+/// in a row.
+const MAX_REJECTED_REFRESHES: u32 = 3;
+
+/// What is needed to fetch a student's data without the user being present.
+#[derive(Debug, Clone)]
+pub struct FeedCredentials {
+    pub url: InstituteCode,
+    pub refresh_token: String,
+}
+
+/// Maps long-lived feed tokens, which are safe to put in calendar subscription
+/// urls, to the Kreta refresh token of the student they belong to.
+///
+/// The stored token is replaced each time a feed is served. Feeds are saved in
+/// the student store when it is enabled, so they survive restarts, otherwise
+/// they are only kept in memory.
+pub struct FeedRegistry {
+    feeds: Mutex<HashMap<String, FeedCredentials>>,
+    /// One refresh at a time per feed, guarding the number of refreshes
+    /// Kreta rejected in a row.
+    refreshes: Mutex<HashMap<String, Arc<tokio::sync::Mutex<u32>>>>,
+    store: web::Data<StudentStore>,
+}
+
+impl FeedRegistry {
+    pub fn new(store: web::Data<StudentStore>) -> FeedRegistry {
+        FeedRegistry {
+            feeds: Mutex::new(HashMap::new()),
+            refreshes: Mutex::new(HashMap::new()),
+            store,
+        }
+    }
+
+    /// Gets an access token for the student of a feed with its refresh token,
+    /// saving the new refresh token. Calendar apps often fetch a feed several
+    /// times at once, so refreshes of a feed wait for each other instead of
+    /// spending the same refresh token twice.
+    pub async fn access_token(
+        &self,
+        feed_token: &str,
+    ) -> Result<(InstituteCode, String), KretaError> {
+        let refresh = self.refresh_lock(feed_token);
+        let mut rejected = refresh.lock().await;
+
+        // Read after locking, so the token rotated by the previous refresh is used
+        let credentials = self
+            .get(feed_token)
+            .await?
+            .ok_or(KretaError::FeedNotFound)?;
+        match refresh_access_token(&credentials.url, &credentials.refresh_token).await {
+            Ok(authentication) => {
+                *rejected = 0;
+                self.update_refresh_token(feed_token, authentication.refresh_token)
+                    .await?;
+                Ok((credentials.url, authentication.access_token))
+            }
+            Err(err @ KretaError::InvalidGrant(_)) => {
+                *rejected += 1;
+                if *rejected >= MAX_REJECTED_REFRESHES {
+                    info!("Removing a calendar feed as its refresh token keeps being rejected");
+                    self.remove(feed_token).await?;
+                }
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn refresh_lock(&self, feed_token: &str) -> Arc<tokio::sync::Mutex<u32>> {
+        let mut refreshes = self.refreshes.lock().unwrap();
+        if !refreshes.contains_key(feed_token) {
+            // Feeds nobody is fetching only have to be kept while they have
+            // rejections to count
+            refreshes.retain(|_, refresh| {
+                Arc::strong_count(refresh) > 1
+                    || refresh.try_lock().map_or(true, |rejected| *rejected > 0)
+            });
+        }
+        refreshes
+            .entry(feed_token.to_string())
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(0)))
+            .clone()
+    }
+
+    pub async fn register(&self, credentials: FeedCredentials) -> Result<String, KretaError> {
+        let feed_token: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(FEED_TOKEN_LENGTH)
+            .collect();
+
+        if self.store.is_enabled() {
+            self.store.add_feed(&feed_token, &credentials).await?;
+        } else {
+            self.feeds
+                .lock()
+                .unwrap()
+                .insert(feed_token.clone(), credentials);
+        }
+
+        Ok(feed_token)
+    }
+
+    async fn get(&self, feed_token: &str) -> Result<Option<FeedCredentials>, KretaError> {
+        if self.store.is_enabled() {
+            return self.store.feed(feed_token).await;
+        }
+        Ok(self.feeds.lock().unwrap().get(feed_token).cloned())
+    }
+
+    async fn update_refresh_token(
+        &self,
+        feed_token: &str,
+        refresh_token: String,
+    ) -> Result<(), KretaError> {
+        if self.store.is_enabled() {
+            return self
+                .store
+                .update_feed_token(feed_token, &refresh_token)
+                .await;
+        }
+        if let Some(credentials) = self.feeds.lock().unwrap().get_mut(feed_token) {
+            credentials.refresh_token = refresh_token;
+        }
+        Ok(())
+    }
+
+    /// Revokes a feed, returning whether it existed.
+    pub async fn remove(&self, feed_token: &str) -> Result<bool, KretaError> {
+        if self.store.is_enabled() {
+            return self.store.remove_feed(feed_token).await;
+        }
+        Ok(self.feeds.lock().unwrap().remove(feed_token).is_some())
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedResponse {
+    pub feed_token: String,
+    pub calendar_path: String,
+}
@@ -0,0 +1,48 @@
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthResponse {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<UpstreamStatus>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamStatus {
+    pub reachable: bool,
+    pub elapsed_millis: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionResponse {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_date: String,
+    pub profile: &'static str,
+}
+
+impl VersionResponse {
+    pub fn current() -> VersionResponse {
+        VersionResponse {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("GIT_COMMIT"),
+            build_date: env!("BUILD_TIMESTAMP")
+                .parse()
+                .map(|timestamp| Utc.timestamp(timestamp, 0).to_rfc3339())
+                .unwrap_or_else(|_err| String::from("unknown")),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+        }
+    }
+}
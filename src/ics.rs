@@ -0,0 +1,285 @@
+use chrono::{NaiveDate, Utc};
+
+use crate::resources::{Homework, Lesson, Task};
+
+static PRODUCT_ID: &str = "-//hazizz//kreta-proxy//HU";
+static TIMEZONE_ID: &str = "Europe/Budapest";
+
+static BUDAPEST_TIMEZONE: &[&str] = &[
+    "BEGIN:VTIMEZONE",
+    "TZID:Europe/Budapest",
+    "BEGIN:DAYLIGHT",
+    "TZOFFSETFROM:+0100",
+    "TZOFFSETTO:+0200",
+    "TZNAME:CEST",
+    "DTSTART:19700329T020000",
+    "RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+    "END:DAYLIGHT",
+    "BEGIN:STANDARD",
+    "TZOFFSETFROM:+0200",
+    "TZOFFSETTO:+0100",
+    "TZNAME:CET",
+    "DTSTART:19701025T030000",
+    "RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU",
+    "END:STANDARD",
+    "END:VTIMEZONE",
+];
+
+/// An RFC 5545 calendar which is built component by component
+/// and rendered with CRLF line endings and folded content lines.
+pub struct Calendar {
+    content: String,
+    time_stamp: String,
+}
+
+impl Calendar {
+    pub fn new(name: &str) -> Calendar {
+        let mut calendar = Calendar {
+            content: String::new(),
+            time_stamp: Utc::now().format("%Y%m%dT%H%M%SZ").to_string(),
+        };
+
+        calendar.push_line("BEGIN:VCALENDAR");
+        calendar.push_line("VERSION:2.0");
+        calendar.push_line(&format!("PRODID:{}", PRODUCT_ID));
+        calendar.push_line("CALSCALE:GREGORIAN");
+        calendar.push_line("METHOD:PUBLISH");
+        calendar.push_line(&format!("X-WR-CALNAME:{}", escape_text(name)));
+        calendar.push_line(&format!("X-WR-TIMEZONE:{}", TIMEZONE_ID));
+        for line in BUDAPEST_TIMEZONE {
+            calendar.push_line(line);
+        }
+
+        calendar
+    }
+
+    pub fn add_lesson(&mut self, lesson: &Lesson) {
+        let date = lesson.date.replace("-", "");
+        // Kreta only names the stand-in of substituted lessons
+        let teacher = if lesson.stand_in {
+            format!("Helyettesítő tanár: {}", lesson.teacher)
+        } else {
+            format!("Tanár: {}", lesson.teacher)
+        };
+        let description = [
+            teacher,
+            format!("Osztály: {}", lesson.class_name),
+            format!("Téma: {}", lesson.topic),
+        ];
+
+        self.push_line("BEGIN:VEVENT");
+        self.push_line(&format!(
+            "UID:{}-{}-{}@kreta-proxy",
+            date,
+            lesson.period_number,
+            uid_part(&lesson.subject)
+        ));
+        self.push_line(&format!("DTSTAMP:{}", self.time_stamp));
+        self.push_line(&format!(
+            "DTSTART;TZID={}:{}T{}",
+            TIMEZONE_ID,
+            date,
+            lesson.start_of_class.replace(":", "")
+        ));
+        self.push_line(&format!(
+            "DTEND;TZID={}:{}T{}",
+            TIMEZONE_ID,
+            date,
+            lesson.end_of_class.replace(":", "")
+        ));
+        self.push_line(&format!("SUMMARY:{}", escape_text(&lesson.subject)));
+        self.push_line(&format!("LOCATION:{}", escape_text(&lesson.room)));
+        self.push_line(&format!(
+            "DESCRIPTION:{}",
+            escape_text(&description.join("\n"))
+        ));
+        if lesson.cancelled {
+            self.push_line("STATUS:CANCELLED");
+        } else {
+            self.push_line("STATUS:CONFIRMED");
+        }
+        self.push_line("END:VEVENT");
+    }
+
+    /// Adds an announced test as an all-day event on its due date.
+    pub fn add_task(&mut self, task: &Task) {
+        self.push_line("BEGIN:VEVENT");
+        self.push_line(&format!("UID:task-{}@kreta-proxy", task.id));
+        self.push_line(&format!("DTSTAMP:{}", self.time_stamp));
+        self.push_line(&format!("DTSTART;VALUE=DATE:{}", all_day(&task.due_date)));
+        if let Some(end) = next_day(&task.due_date) {
+            self.push_line(&format!("DTEND;VALUE=DATE:{}", end));
+        }
+        self.push_line(&format!(
+            "SUMMARY:{}",
+            escape_text(&format!("{}: {}", task.subject, task.topic))
+        ));
+        self.push_line(&format!(
+            "DESCRIPTION:{}",
+            escape_text(&format!(
+                "Tantárgy: {}\nTanár: {}\nSzámonkérés módja: {}",
+                task.subject, task.teacher, task.grade_type
+            ))
+        ));
+        self.push_line(&format!("CATEGORIES:{}", escape_text(&task.grade_type)));
+        self.push_line("END:VEVENT");
+    }
+
+    /// Adds a homework as a to-do which is due on its deadline.
+    pub fn add_homework(&mut self, homework: &Homework) {
+        self.push_line("BEGIN:VTODO");
+        self.push_line(&format!("UID:homework-{}@kreta-proxy", homework.id));
+        self.push_line(&format!("DTSTAMP:{}", self.time_stamp));
+        self.push_line(&format!(
+            "DTSTART;VALUE=DATE:{}",
+            all_day(&homework.creation_date)
+        ));
+        self.push_line(&format!("DUE;VALUE=DATE:{}", all_day(&homework.due_date)));
+        self.push_line(&format!(
+            "SUMMARY:{}",
+            escape_text(&format!("Házi feladat: {}", homework.subject))
+        ));
+        self.push_line(&format!(
+            "DESCRIPTION:{}",
+            escape_text(&format!(
+                "{}\nTantárgy: {}\nTanár: {}",
+                homework.content, homework.subject, homework.teacher
+            ))
+        ));
+        self.push_line("STATUS:NEEDS-ACTION");
+        self.push_line("END:VTODO");
+    }
+
+    pub fn finish(mut self) -> String {
+        self.push_line("END:VCALENDAR");
+        self.content
+    }
+
+    fn push_line(&mut self, line: &str) {
+        fold_line(&mut self.content, line);
+    }
+}
+
+pub fn schedule_to_calendar(lessons: &[Lesson]) -> String {
+    let mut calendar = Calendar::new("Órarend");
+    for lesson in lessons {
+        calendar.add_lesson(lesson);
+    }
+    calendar.finish()
+}
+
+pub fn assignments_to_calendar(tasks: &[Task], homework: &[Homework]) -> String {
+    let mut calendar = Calendar::new("Számonkérések és házi feladatok");
+    for task in tasks {
+        calendar.add_task(task);
+    }
+    for hw in homework {
+        calendar.add_homework(hw);
+    }
+    calendar.finish()
+}
+
+fn all_day(date: &str) -> String {
+    date.replace("-", "")
+}
+
+fn next_day(date: &str) -> Option<String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .ok()
+        .map(|date| date.succ().format("%Y%m%d").to_string())
+}
+
+/// Escapes a TEXT property value as described in RFC 5545 section 3.3.11.
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Keeps only the characters of `text` which are safe to put in a UID.
+fn uid_part(text: &str) -> String {
+    text.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// Appends `line` to `out`, folding it to lines of at most 75 octets
+/// without splitting multi-byte characters (RFC 5545 section 3.1).
+fn fold_line(out: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            length = 1;
+        }
+        out.push(c);
+        length += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod ics_test {
+    use super::*;
+    use crate::resources::UnrefinedLesson;
+
+    fn lesson(state_name: &str, teacher: &str) -> Lesson {
+        let unrefined: UnrefinedLesson = serde_json::from_value(serde_json::json!({
+            "Count": 2,
+            "Date": "2020-01-06T00:00:00",
+            "StartTime": "2020-01-06T08:55:00",
+            "EndTime": "2020-01-06T09:40:00",
+            "Nev": "Matematika",
+            "ClassRoom": "104",
+            "ClassGroup": "11.B",
+            "Teacher": teacher,
+            "StateName": state_name,
+            "Theme": "Deriválás, szabályok; gyakorlás",
+            "TeacherHomeworkId": null
+        }))
+        .unwrap();
+        unrefined.refine()
+    }
+
+    #[test]
+    fn test_escape_text() {
+        assert_eq!(escape_text("a;b,c\\d\ne"), "a\\;b\\,c\\\\d\\ne");
+    }
+
+    #[test]
+    fn test_fold_line() {
+        let mut out = String::new();
+        fold_line(&mut out, &"é".repeat(50));
+        for line in out.split("\r\n") {
+            assert!(line.len() <= 75, "{}", line);
+        }
+        assert_eq!(out.replace("\r\n ", ""), format!("{}\r\n", "é".repeat(50)));
+    }
+
+    #[test]
+    fn test_schedule_to_calendar() {
+        let calendar = schedule_to_calendar(&[
+            lesson("Órarendi óra", "Kiss Anna"),
+            lesson("Elmaradt tanóra", "Helyettes: Nagy Béla"),
+        ]);
+
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(calendar.ends_with("END:VCALENDAR\r\n"));
+        assert!(calendar.contains("TZID:Europe/Budapest\r\n"));
+        assert!(calendar.contains("DTSTART;TZID=Europe/Budapest:20200106T"));
+        assert!(calendar.contains("STATUS:CONFIRMED\r\n"));
+        assert!(calendar.contains("STATUS:CANCELLED\r\n"));
+        assert!(calendar.contains("Helyettesítő tanár: Nagy Béla"));
+        assert!(!calendar.contains("Tanár: Nagy Béla"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::warn;
+use once_cell::sync::Lazy;
+
+use crate::config;
+use crate::error::KretaError;
+use crate::requests::get_schools;
+
+/// Institute codes of Kreta are far shorter than this.
+const MAX_LENGTH: usize = 32;
+
+/// The institute codes from `/Institute` with the time they were fetched.
+static KNOWN_INSTITUTES: Lazy<Mutex<Option<(Instant, HashSet<String>)>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// A validated institute code, safe to put in the host name of Kreta urls.
+///
+/// Only lowercase ascii letters, digits and inner dashes are allowed, so the
+/// code can't change the host, path or query of the urls it ends up in.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InstituteCode(String);
+
+impl InstituteCode {
+    pub fn parse(code: &str) -> Result<InstituteCode, KretaError> {
+        let code = code.trim().to_lowercase();
+        let valid = !code.is_empty()
+            && code.len() <= MAX_LENGTH
+            && !code.starts_with('-')
+            && !code.ends_with('-')
+            && code
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+        if valid {
+            Ok(InstituteCode(code))
+        } else {
+            Err(KretaError::InvalidInstitute(code))
+        }
+    }
+
+    /// Parses `code`, also checking it against the institutes Kreta knows
+    /// about when `upstream.verify_institutes` is enabled.
+    pub async fn validate(code: &str) -> Result<InstituteCode, KretaError> {
+        let institute = InstituteCode::parse(code)?;
+        if !config::get().upstream.verify_institutes || is_known(&institute).await {
+            Ok(institute)
+        } else {
+            Err(KretaError::InvalidInstitute(institute.0))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for InstituteCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The label of `institute` in metrics: its code when it is on the fetched
+/// institute list (or is the global api), `other` otherwise, so made up codes
+/// can't add an unbounded number of series.
+pub fn metric_label(institute: &str) -> &str {
+    if institute == "global" {
+        return institute;
+    }
+    match &*KNOWN_INSTITUTES.lock().unwrap() {
+        Some((_, codes)) if codes.contains(institute) => institute,
+        _ => "other",
+    }
+}
+
+/// Looks `institute` up in the cached institute list, fetching it when it is
+/// missing or too old. When the list can't be fetched every code is accepted,
+/// so an outage of the global api doesn't break every request.
+async fn is_known(institute: &InstituteCode) -> bool {
+    let ttl = config::get().upstream.institute_list_ttl();
+    if let Some((fetched, codes)) = &*KNOWN_INSTITUTES.lock().unwrap() {
+        if fetched.elapsed() < ttl {
+            return codes.contains(institute.as_str());
+        }
+    }
+
+    match fetch_known_institutes().await {
+        Ok(codes) => codes.contains(institute.as_str()),
+        Err(err) => {
+            warn!("Institute list couldn't be fetched: {}", err);
+            true
+        }
+    }
+}
+
+/// Fetches the institute list every `upstream.institute_list_ttl_secs`, so
+/// metrics can label calls by institute without `verify_institutes` too.
+pub async fn refresh_known_institutes() {
+    let mut interval = tokio::time::interval(config::get().upstream.institute_list_ttl());
+    loop {
+        interval.tick().await;
+        if let Err(err) = fetch_known_institutes().await {
+            warn!("Institute list couldn't be fetched: {}", err);
+        }
+    }
+}
+
+async fn fetch_known_institutes() -> Result<HashSet<String>, KretaError> {
+    let codes: HashSet<String> = get_schools()
+        .await?
+        .into_iter()
+        .map(|school| school.institute_code.to_lowercase())
+        .collect();
+    *KNOWN_INSTITUTES.lock().unwrap() = Some((Instant::now(), codes.clone()));
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod institute_test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            InstituteCode::parse(" KLIK035220001 ").unwrap().as_str(),
+            "klik035220001"
+        );
+        assert!(InstituteCode::parse("some-school").is_ok());
+
+        for invalid in &[
+            "",
+            "-klik",
+            "klik-",
+            "evil.com/",
+            "klik@evil.com",
+            "klik#",
+            "klik?x=1",
+            "klik.evil",
+            "klik/../idp",
+            "ékrét",
+        ] {
+            assert!(InstituteCode::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(InstituteCode::parse(&"a".repeat(MAX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_metric_label() {
+        *KNOWN_INSTITUTES.lock().unwrap() = Some((
+            Instant::now(),
+            vec![String::from("klik035220001")].into_iter().collect(),
+        ));
+        assert_eq!(metric_label("klik035220001"), "klik035220001");
+        assert_eq!(metric_label("global"), "global");
+        assert_eq!(metric_label("made-up-0001"), "other");
+    }
+}
@@ -1,19 +1,59 @@
 use std::collections::BTreeMap;
+use std::sync::Arc;
 use std::time::Instant;
 
+use actix_cors::Cors;
+use actix_web::dev::{BodyEncoding, Service};
+use actix_web::http::header::ContentEncoding;
 use actix_web::*;
-use chrono::{Date, Datelike, NaiveDate, Utc};
+use chrono::{Date, DateTime, Datelike, Duration, NaiveDate, Utc};
 use http::StatusCode;
-use log::info;
+use log::{error, info};
 use serde::Deserialize;
 
+use crate::access_log::AccessLog;
+use crate::cache::{with_cache_control, Resource};
+use crate::changes::{detect, ChangesResponse};
+use crate::compression::skip_small_body;
+use crate::config::Config;
 use crate::error::KretaError;
+use crate::etag::conditional_get;
+use crate::event_stream::Session;
+use crate::feeds::{FeedCredentials, FeedRegistry, FeedResponse};
+use crate::health::{HealthResponse, UpstreamStatus, VersionResponse};
+use crate::ics::{assignments_to_calendar, schedule_to_calendar};
+use crate::institute::InstituteCode;
+use crate::rate_limit::{RateLimit, RateLimiter};
 use crate::requests::*;
 use crate::resources::*;
-
+use crate::response::{csv_response, Format, DOCUMENT_FORMATS, TABULAR_FORMATS};
+use crate::store::{Stored, StudentStore};
+use crate::subscriptions::{parse_webhook_url, Subscription, SubscriptionResponse};
+
+mod access_log;
+mod cache;
+mod changes;
+mod compression;
+mod config;
+mod context;
+mod discord;
 mod error;
+mod etag;
+mod event_stream;
+mod feeds;
+mod health;
+mod ics;
+mod institute;
+mod logging;
+mod messages;
+mod metrics;
+mod rate_limit;
 mod requests;
 mod resources;
+mod response;
+mod store;
+mod subscriptions;
+mod upstream;
 
 #[derive(Debug, Deserialize)]
 pub struct TokenCreationQuery {
@@ -22,10 +62,25 @@ pub struct TokenCreationQuery {
     password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct FeedCreationQuery {
+    url: String,
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscriptionCreationQuery {
+    url: String,
+    refresh_token: String,
+    webhook_url: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GeneralQuery {
     token: String,
     url: String,
+    #[serde(default)]
+    format: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,6 +91,31 @@ pub struct DateBasedQuery {
     from_date: String,
     #[serde(default)]
     to_date: String,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangesQuery {
+    token: String,
+    url: String,
+    /// Id of the last change seen, the `cursor` of the previous answer.
+    #[serde(default)]
+    after: Option<i64>,
+    /// Time to start from when there is no cursor yet.
+    #[serde(default)]
+    since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    format: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventStreamQuery {
+    token: String,
+    url: String,
+    /// For clients which can't send the `Last-Event-ID` header.
+    #[serde(default)]
+    last_event_id: Option<i64>,
 }
 
 impl Default for DateBasedQuery {
@@ -50,220 +130,557 @@ impl Default for DateBasedQuery {
                 .unwrap()
                 .format("%Y-%m-%d")
                 .to_string(),
+            format: None,
         }
     }
 }
 
-async fn handle_school_request(_req: HttpRequest) -> Result<HttpResponse, KretaError> {
+async fn handle_school_request(req: HttpRequest) -> Result<HttpResponse, KretaError> {
     let schools: Vec<School> = get_schools().await?;
 
-    Ok(HttpResponse::build(StatusCode::from_u16(200).unwrap()).json(schools))
+    Format::negotiate(&req, None, DOCUMENT_FORMATS).respond(&schools)
 }
 
 #[actix_web::get("/grades")]
 async fn handle_grades_request(
+    req: HttpRequest,
     query: web::Query<GeneralQuery>,
+    store: web::Data<StudentStore>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let grades = get_grades(&query.token, &query.url).await?;
-
-    info!(
-        "Grade request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
-
-    Ok(HttpResponse::build(StatusCode::OK).json(grades))
+    let institute = InstituteCode::validate(&query.url).await?;
+    let grades = store
+        .fetch(
+            &query.token,
+            &institute,
+            "grades",
+            get_grades(&query.token, &institute),
+        )
+        .await?;
+
+    let response = match Format::negotiate(&req, query.format.as_deref(), TABULAR_FORMATS) {
+        Format::Csv => csv_response("grades", grades.data.values().flatten()),
+        format => format.respond(&grades.data),
+    }?;
+
+    Ok(grades.mark(with_cache_control(response, Resource::Profile)))
 }
 
 #[actix_web::get("/notes")]
-async fn handle_notes_request(query: web::Query<GeneralQuery>) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let notes = get_notes(&query.token, &query.url).await?;
-
-    info!(
-        "Notes request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
-
-    Ok(HttpResponse::build(StatusCode::OK).json(notes))
+async fn handle_notes_request(
+    req: HttpRequest,
+    query: web::Query<GeneralQuery>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    let institute = InstituteCode::validate(&query.url).await?;
+    let notes = store
+        .fetch(
+            &query.token,
+            &institute,
+            "notes",
+            get_notes(&query.token, &institute),
+        )
+        .await?;
+
+    let response = match Format::negotiate(&req, query.format.as_deref(), TABULAR_FORMATS) {
+        Format::Csv => csv_response("notes", &notes.data),
+        format => format.respond(&notes.data),
+    }?;
+
+    Ok(notes.mark(with_cache_control(response, Resource::Profile)))
 }
 
 #[actix_web::get("/averages")]
 async fn handle_averages_request(
+    req: HttpRequest,
     query: web::Query<GeneralQuery>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let averages = get_averages(&query.token, &query.url).await?;
+    let institute = InstituteCode::validate(&query.url).await?;
+    let averages = get_averages(&query.token, &institute).await?;
 
-    info!(
-        "Averages request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
+    let response = match Format::negotiate(&req, query.format.as_deref(), TABULAR_FORMATS) {
+        Format::Csv => csv_response("averages", &averages),
+        format => format.respond(&averages),
+    }?;
 
-    Ok(HttpResponse::build(StatusCode::OK).json(averages))
+    Ok(with_cache_control(response, Resource::Profile))
 }
 
 #[actix_web::get("/v2/schedules")]
 async fn handle_schedule_request_v2(
+    req: HttpRequest,
     query: web::Query<DateBasedQuery>,
+    store: web::Data<StudentStore>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let lessons_sorted = get_schedule_v2(
-        query.token.clone(),
-        query.url.clone(),
-        query.from_date.clone(),
-        query.to_date.clone(),
-    )
-    .await?;
-
-    info!(
-        "Schedule V2 request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
-
-    Ok(HttpResponse::build(StatusCode::OK).json(lessons_sorted))
+    let institute = InstituteCode::validate(&query.url).await?;
+    let lessons_sorted = store
+        .fetch(
+            &query.token,
+            &institute,
+            &format!("schedule_v2:{}:{}", query.from_date, query.to_date),
+            get_schedule_v2(
+                query.token.clone(),
+                &institute,
+                query.from_date.clone(),
+                query.to_date.clone(),
+            ),
+        )
+        .await?;
+
+    Format::negotiate(&req, query.format.as_deref(), DOCUMENT_FORMATS)
+        .respond(&lessons_sorted.data)
+        .map(|response| lessons_sorted.mark(with_cache_control(response, Resource::Schedule)))
 }
 
 #[actix_web::get("/schedules")]
 async fn handle_schedule_request(
+    req: HttpRequest,
     query: web::Query<DateBasedQuery>,
+    store: web::Data<StudentStore>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let lessons: Vec<Lesson> = get_schedule(
-        query.token.clone(),
-        query.url.clone(),
-        query.from_date.clone(),
-        query.to_date.clone(),
-    )
-    .await?;
-
-    info!(
-        "Schedule request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
+    let institute = InstituteCode::validate(&query.url).await?;
+    let lessons: Stored<Vec<Lesson>> = store
+        .fetch(
+            &query.token,
+            &institute,
+            &format!("lessons:{}:{}", query.from_date, query.to_date),
+            get_schedule(
+                query.token.clone(),
+                &institute,
+                query.from_date.clone(),
+                query.to_date.clone(),
+            ),
+        )
+        .await?;
+
+    Format::negotiate(&req, query.format.as_deref(), DOCUMENT_FORMATS)
+        .respond(&lessons.data)
+        .map(|response| lessons.mark(with_cache_control(response, Resource::Schedule)))
+}
 
-    Ok(HttpResponse::build(StatusCode::OK).json(lessons))
+#[actix_web::get("/schedules.ics")]
+async fn handle_schedule_calendar_request(
+    query: web::Query<DateBasedQuery>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    let institute = InstituteCode::validate(&query.url).await?;
+    let lessons: Stored<Vec<Lesson>> = store
+        .fetch(
+            &query.token,
+            &institute,
+            &format!("lessons:{}:{}", query.from_date, query.to_date),
+            get_schedule(
+                query.token.clone(),
+                &institute,
+                query.from_date.clone(),
+                query.to_date.clone(),
+            ),
+        )
+        .await?;
+
+    let calendar = schedule_to_calendar(&lessons.data);
+
+    Ok(lessons.mark(with_cache_control(
+        HttpResponse::build(StatusCode::OK)
+            .content_type("text/calendar; charset=utf-8")
+            .body(calendar),
+        Resource::Schedule,
+    )))
 }
 
 #[actix_web::get("/tasks")]
 async fn handle_tasks_request(
+    req: HttpRequest,
     query: web::Query<DateBasedQuery>,
+    store: web::Data<StudentStore>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let tasks = get_tasks(&query.token, &query.url, &query.from_date, &query.to_date).await?;
-
-    info!(
-        "Tasks request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
-
-    Ok(HttpResponse::build(StatusCode::OK).json(tasks))
+    let institute = InstituteCode::validate(&query.url).await?;
+    let tasks = store
+        .fetch(
+            &query.token,
+            &institute,
+            &format!("tasks:{}:{}", query.from_date, query.to_date),
+            get_tasks(&query.token, &institute, &query.from_date, &query.to_date),
+        )
+        .await?;
+
+    let response = match Format::negotiate(&req, query.format.as_deref(), TABULAR_FORMATS) {
+        Format::Csv => csv_response("tasks", &tasks.data),
+        format => format.respond(&tasks.data),
+    }?;
+
+    Ok(tasks.mark(with_cache_control(response, Resource::Tasks)))
 }
 
 #[actix_web::get("/homework")]
 async fn handle_homework_request(
+    req: HttpRequest,
     query: web::Query<GeneralQuery>,
+    store: web::Data<StudentStore>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
-
-    let tasks = get_homework(query.token.clone(), query.url.clone()).await?;
-
-    info!(
-        "Homework request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
-
-    Ok(HttpResponse::build(StatusCode::OK).json(tasks))
+    let institute = InstituteCode::validate(&query.url).await?;
+    let tasks = store
+        .fetch(
+            &query.token,
+            &institute,
+            "homework",
+            get_homework(query.token.clone(), &institute),
+        )
+        .await?;
+
+    Format::negotiate(&req, query.format.as_deref(), DOCUMENT_FORMATS)
+        .respond(&tasks.data)
+        .map(|response| tasks.mark(with_cache_control(response, Resource::Homework)))
 }
 
 #[actix_web::get("/profile")]
 async fn handle_profile_request(
+    req: HttpRequest,
     query: web::Query<GeneralQuery>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
+    let institute = InstituteCode::validate(&query.url).await?;
+    let profile = get_profile(&query.token, &institute).await?.refine();
 
-    let profile = get_profile(&query.token, &query.url).await?.refine();
+    Format::negotiate(&req, query.format.as_deref(), DOCUMENT_FORMATS)
+        .respond(&profile)
+        .map(|response| with_cache_control(response, Resource::Profile))
+}
 
-    info!(
-        "Profile request done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
-    );
+#[actix_web::get("/changes")]
+async fn handle_changes_request(
+    req: HttpRequest,
+    query: web::Query<ChangesQuery>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    let institute = InstituteCode::validate(&query.url).await?;
+    let (student, _) = detect(&store, &query.token, &institute).await?;
+
+    // Read first, so no change recorded meanwhile falls behind the cursor
+    let last_change_id = store.last_change_id().await?;
+    let changes = match (query.after, query.since) {
+        (Some(after), _) => store.changes_after(&student, after).await?,
+        (None, Some(since)) => store.changes_since(&student, since).await?,
+        (None, None) => store.changes_after(&student, 0).await?,
+    };
+    let changes = ChangesResponse {
+        checked_at: Utc::now(),
+        cursor: changes
+            .last()
+            .map_or(last_change_id, |change| change.id.max(last_change_id)),
+        changes,
+    };
 
-    Ok(HttpResponse::build(StatusCode::OK).json(profile))
+    Format::negotiate(&req, query.format.as_deref(), DOCUMENT_FORMATS).respond(&changes)
+}
+
+#[actix_web::get("/events/stream")]
+async fn handle_event_stream(
+    req: HttpRequest,
+    query: web::Query<EventStreamQuery>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    let institute = InstituteCode::validate(&query.url).await?;
+    let (student, _) = detect(&store, &query.token, &institute).await?;
+
+    let last_event_id = req
+        .headers()
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(query.last_event_id);
+    // Only accepted as a header, queries end up in logs and browser histories
+    let refresh_token = req
+        .headers()
+        .get("x-refresh-token")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string());
+    let session = Session {
+        student,
+        token: query.token.clone(),
+        refresh_token,
+        institute,
+    };
+
+    // Compressed streams would be buffered instead of reaching the client
+    Ok(HttpResponse::build(StatusCode::OK)
+        .encoding(ContentEncoding::Identity)
+        .content_type("text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .streaming(event_stream::start(store, session, last_event_id)))
 }
 
 #[actix_web::post("/token")]
 async fn handle_create_token(
+    req: HttpRequest,
     query: web::Query<TokenCreationQuery>,
 ) -> Result<HttpResponse, KretaError> {
-    let request_started = Instant::now();
+    let institute = InstituteCode::validate(&query.url).await?;
+    let lessons_sorted = create_token(&institute, &query.username, &query.password).await?;
+
+    Format::negotiate(&req, None, DOCUMENT_FORMATS).respond(&lessons_sorted)
+}
+
+#[actix_web::post("/feeds")]
+async fn handle_create_feed(
+    query: web::Query<FeedCreationQuery>,
+    feeds: web::Data<FeedRegistry>,
+) -> Result<HttpResponse, KretaError> {
+    let institute = InstituteCode::validate(&query.url).await?;
+
+    // Make sure the refresh token works before handing out a feed for it, the
+    // rotated token is only known by the feed from now on
+    let authentication = refresh_access_token(&institute, &query.refresh_token).await?;
+
+    let feed_token = feeds
+        .register(FeedCredentials {
+            url: institute,
+            refresh_token: authentication.refresh_token,
+        })
+        .await?;
+
+    Ok(HttpResponse::build(StatusCode::OK).json(FeedResponse {
+        calendar_path: format!("/feeds/{}/assignments.ics", feed_token),
+        feed_token,
+    }))
+}
+
+#[actix_web::get("/feeds/{feed_token}/assignments.ics")]
+async fn handle_assignments_feed_request(
+    feed_token: web::Path<String>,
+    feeds: web::Data<FeedRegistry>,
+) -> Result<HttpResponse, KretaError> {
+    let (institute, access_token) = feeds.access_token(&feed_token).await?;
+
+    let now: Date<_> = Utc::now().date();
+    let tasks = get_tasks(
+        &access_token,
+        &institute,
+        &(now - Duration::days(30)).format("%Y-%m-%d").to_string(),
+        &(now + Duration::days(365)).format("%Y-%m-%d").to_string(),
+    )
+    .await?;
+    let homework = get_homework(access_token, &institute).await?;
 
-    let lessons_sorted = create_token(&query.url, &query.username, &query.password).await?;
+    let calendar = assignments_to_calendar(&tasks, &homework);
 
-    info!(
-        "Token creation done for {} in {}",
-        &query.url,
-        request_started.elapsed().as_millis()
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/calendar; charset=utf-8")
+        .body(calendar))
+}
+
+#[actix_web::delete("/feeds/{feed_token}")]
+async fn handle_delete_feed(
+    feed_token: web::Path<String>,
+    feeds: web::Data<FeedRegistry>,
+) -> Result<HttpResponse, KretaError> {
+    if feeds.remove(&feed_token).await? {
+        Ok(HttpResponse::build(StatusCode::NO_CONTENT).finish())
+    } else {
+        Err(KretaError::FeedNotFound)
+    }
+}
+
+#[actix_web::post("/subscriptions")]
+async fn handle_create_subscription(
+    query: web::Query<SubscriptionCreationQuery>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    if !config::get().subscriptions.enabled {
+        return Err(KretaError::SubscriptionsDisabled);
+    }
+    let institute = InstituteCode::validate(&query.url).await?;
+    let webhook_url = parse_webhook_url(&query.webhook_url).await?;
+
+    // Make sure the refresh token works before polling with it, the rotated
+    // token is only known by the subscription from now on
+    let authentication = refresh_access_token(&institute, &query.refresh_token).await?;
+
+    // Only changes detected from now on are delivered
+    let subscription = Subscription::new(
+        institute,
+        authentication.refresh_token,
+        webhook_url,
+        store.last_change_id().await?,
     );
+    store.add_subscription(&subscription).await?;
 
-    Ok(HttpResponse::build(StatusCode::OK).json(lessons_sorted))
+    Ok(
+        HttpResponse::build(StatusCode::OK).json(SubscriptionResponse {
+            subscription_id: subscription.id,
+            secret: subscription.secret,
+        }),
+    )
+}
+
+#[actix_web::delete("/subscriptions/{subscription_id}")]
+async fn handle_delete_subscription(
+    subscription_id: web::Path<String>,
+    store: web::Data<StudentStore>,
+) -> Result<HttpResponse, KretaError> {
+    if !config::get().subscriptions.enabled {
+        return Err(KretaError::SubscriptionsDisabled);
+    }
+    if store.remove_subscription(&subscription_id).await? {
+        Ok(HttpResponse::build(StatusCode::NO_CONTENT).finish())
+    } else {
+        Err(KretaError::SubscriptionNotFound)
+    }
+}
+
+#[actix_web::get("/healthz")]
+async fn handle_health_request() -> HttpResponse {
+    HttpResponse::build(StatusCode::OK).json(HealthResponse {
+        status: "ok",
+        upstream: None,
+    })
+}
+
+#[actix_web::get("/readyz")]
+async fn handle_readiness_request() -> HttpResponse {
+    let health = &config::get().health;
+    if !health.probe_upstream {
+        return HttpResponse::build(StatusCode::OK).json(HealthResponse {
+            status: "ready",
+            upstream: None,
+        });
+    }
+
+    let probe_started = Instant::now();
+    let probe = probe_global_api(health.probe_timeout()).await;
+    let upstream = UpstreamStatus {
+        reachable: probe.is_ok(),
+        elapsed_millis: probe_started.elapsed().as_millis(),
+        error: probe.err().map(|err| format!("{}", err)),
+    };
+
+    if upstream.reachable {
+        HttpResponse::build(StatusCode::OK).json(HealthResponse {
+            status: "ready",
+            upstream: Some(upstream),
+        })
+    } else {
+        HttpResponse::build(StatusCode::SERVICE_UNAVAILABLE).json(HealthResponse {
+            status: "unavailable",
+            upstream: Some(upstream),
+        })
+    }
+}
+
+#[actix_web::get("/version")]
+async fn handle_version_request() -> HttpResponse {
+    HttpResponse::build(StatusCode::OK).json(VersionResponse::current())
+}
+
+#[actix_web::get("/metrics")]
+async fn handle_metrics_request() -> HttpResponse {
+    let (content_type, body) = metrics::render();
+
+    HttpResponse::build(StatusCode::OK)
+        .content_type(content_type)
+        .body(body)
 }
 
 #[actix_rt::main]
 async fn main() {
-    let port: u32 = match std::env::var("SERVER_PORT") {
-        Ok(port_string) => match port_string.parse() {
-            Ok(port_parsed) => port_parsed,
-            Err(_err) => {
-                println!("port {} couldn't be parsed!", port_string);
-                return;
-            }
-        },
-        Err(_err) => {
-            println!("server.port variable isn't present, defaulting to 9110");
-            9110
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
         }
     };
 
-    let address: String = match std::env::var("SERVER_ADDRESS") {
-        Ok(address) => address,
-        Err(_err) => String::from("127.0.0.1"),
+    logging::init(&config.log);
+
+    config::init(config.clone());
+    metrics::init();
+
+    let address = format!("{}:{}", &config.server.address, &config.server.port);
+    let compression_min_size = config.compression.min_size;
+    let cors_config = config.cors.clone();
+    let store = match StudentStore::open(&config.store) {
+        Ok(store) => web::Data::new(store),
+        Err(err) => {
+            error!("Store couldn't be opened at {}: {}", config.store.path, err);
+            std::process::exit(1);
+        }
     };
+    let feeds = web::Data::new(FeedRegistry::new(store.clone()));
+    actix_rt::spawn(institute::refresh_known_institutes());
+    if config.subscriptions.enabled {
+        actix_rt::spawn(subscriptions::poll(store.clone()));
+    }
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limit.clone()));
+    if config.rate_limit.enabled {
+        actix_rt::spawn(rate_limit::prune(rate_limiter.clone()));
+    }
 
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    let server = HttpServer::new(move || {
+        let mut cors = Cors::new().max_age(cors_config.max_age_secs);
+        for origin in &cors_config.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
 
-    let server = HttpServer::new(|| {
         App::new()
+            .app_data(feeds.clone())
+            .app_data(store.clone())
+            .wrap_fn(|req, srv| {
+                let method = req.method().clone();
+                let if_none_match = req.headers().get(http::header::IF_NONE_MATCH).cloned();
+                let response = srv.call(req);
+                async move { Ok(conditional_get(&method, if_none_match, response.await?)) }
+            })
+            .wrap_fn(move |req, srv| {
+                let response = srv.call(req);
+                async move {
+                    let mut res = response.await?;
+                    skip_small_body(&mut res, compression_min_size);
+                    Ok(res)
+                }
+            })
+            .wrap(middleware::Compress::default())
+            .wrap(RateLimit(rate_limiter.clone()))
+            .wrap(middleware::Condition::new(
+                cors_config.enabled,
+                cors.finish(),
+            ))
+            .wrap(AccessLog)
             .service(handle_grades_request)
             .service(handle_notes_request)
             .service(handle_averages_request)
             .service(handle_schedule_request)
             .service(handle_schedule_request_v2)
+            .service(handle_schedule_calendar_request)
             .service(handle_tasks_request)
             .service(handle_homework_request)
             .service(handle_profile_request)
+            .service(handle_changes_request)
+            .service(handle_event_stream)
             .service(handle_create_token)
-    })
-    .bind(format!("{}:{}", &address, &port))
-    .unwrap()
-    .run();
+            .service(handle_create_feed)
+            .service(handle_assignments_feed_request)
+            .service(handle_delete_feed)
+            .service(handle_create_subscription)
+            .service(handle_delete_subscription)
+            .service(handle_health_request)
+            .service(handle_readiness_request)
+            .service(handle_version_request)
+            .service(handle_metrics_request)
+    });
+
+    let server = match config.server.workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+
+    let server = match server.bind(&address) {
+        Ok(server) => server.run(),
+        Err(err) => {
+            error!("Server couldn't bind to {}: {}", &address, err);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Server bind to {} with port {}!", &address, &port);
+    info!("Server bind to {}!", &address);
 
     let _ = server.await;
 }
@@ -0,0 +1,37 @@
+use std::io::Write;
+
+use chrono::Utc;
+use serde_json::{Map, Value};
+
+use crate::config::LogConfig;
+use crate::context;
+
+/// Sets up `env_logger`, writing one JSON object per line when `config.json`
+/// is set. Every line logged while handling a request carries its id and
+/// institute, but never the tokens or credentials of the student.
+pub fn init(config: &LogConfig) {
+    let mut builder =
+        env_logger::from_env(env_logger::Env::default().default_filter_or(config.level.as_str()));
+
+    if config.json {
+        builder.format(|buf, record| {
+            let mut line = Map::new();
+            line.insert(String::from("time"), Value::from(Utc::now().to_rfc3339()));
+            line.insert(String::from("level"), Value::from(record.level().as_str()));
+            line.insert(String::from("target"), Value::from(record.target()));
+            line.insert(
+                String::from("message"),
+                Value::from(format!("{}", record.args())),
+            );
+            if let Some(request_id) = context::request_id() {
+                line.insert(String::from("requestId"), Value::from(request_id));
+            }
+            if let Some(institute) = context::institute() {
+                line.insert(String::from("institute"), Value::from(institute));
+            }
+            writeln!(buf, "{}", Value::Object(line))
+        });
+    }
+
+    builder.init();
+}
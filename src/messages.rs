@@ -0,0 +1,301 @@
+use crate::response::by_quality;
+
+/// Languages the error messages are translated to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    English,
+    Hungarian,
+}
+
+impl Language {
+    fn from_tag(tag: &str) -> Option<Language> {
+        let primary = tag.split('-').next().unwrap_or("").trim().to_lowercase();
+        match primary.as_str() {
+            "en" => Some(Language::English),
+            "hu" => Some(Language::Hungarian),
+            _ => None,
+        }
+    }
+
+    /// The language tag sent in the `Content-Language` header.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Hungarian => "hu",
+        }
+    }
+
+    /// Picks the most preferred supported language of an `Accept-Language`
+    /// header, defaulting to English like the messages before translation.
+    pub fn from_accept_language(accept_language: Option<&str>) -> Language {
+        accept_language
+            .and_then(|accept_language| {
+                by_quality(accept_language)
+                    .iter()
+                    .find_map(|tag| Language::from_tag(tag))
+            })
+            .unwrap_or(Language::English)
+    }
+}
+
+/// Title and message of an error in every language.
+struct Message {
+    code: u32,
+    en: (&'static str, &'static str),
+    hu: (&'static str, &'static str),
+}
+
+/// Placeholder replaced with the number of seconds to wait.
+pub static RETRY_AFTER_PLACEHOLDER: &str = "{retry_after}";
+
+/// Error messages keyed by the error codes of `KretaError`.
+static CATALOG: &[Message] = &[
+    Message {
+        code: 20,
+        en: ("Request failed", "Kreta couldn't be reached"),
+        hu: ("Sikertelen kérés", "A Kréta szervere nem érhető el"),
+    },
+    Message {
+        code: 21,
+        en: (
+            "Unrecognisable response from kreta server",
+            "Kreta answered with an unknown error",
+        ),
+        hu: (
+            "Értelmezhetetlen válasz a Kréta szerverétől",
+            "A Kréta ismeretlen hibával válaszolt",
+        ),
+    },
+    Message {
+        code: 22,
+        en: (
+            "Parsing response failed",
+            "Kreta answered with unexpected data",
+        ),
+        hu: (
+            "A válasz feldolgozása sikertelen",
+            "A Kréta váratlan adatokkal válaszolt",
+        ),
+    },
+    Message {
+        code: 23,
+        en: (
+            "Calendar feed not found",
+            "The feed token is unknown or has been revoked",
+        ),
+        hu: (
+            "A naptár nem található",
+            "A naptár azonosítója ismeretlen vagy visszavonták",
+        ),
+    },
+    Message {
+        code: 24,
+        en: (
+            "Serializing response failed",
+            "The response couldn't be created",
+        ),
+        hu: (
+            "A válasz előállítása sikertelen",
+            "A válasz nem hozható létre",
+        ),
+    },
+    Message {
+        code: 25,
+        en: ("Too many requests", "Retry after {retry_after} seconds"),
+        hu: (
+            "Túl sok kérés",
+            "Próbáld újra {retry_after} másodperc múlva",
+        ),
+    },
+    Message {
+        code: 26,
+        en: (
+            "Too many requests waiting for Kreta",
+            "The proxy is overloaded, try again later",
+        ),
+        hu: (
+            "Túl sok kérés vár a Krétára",
+            "A szerver túlterhelt, próbáld újra később",
+        ),
+    },
+    Message {
+        code: 27,
+        en: (
+            "Timed out waiting for Kreta",
+            "Too many requests are in flight to the school, try again later",
+        ),
+        hu: (
+            "Lejárt a várakozási idő",
+            "Túl sok kérés fut az iskola felé, próbáld újra később",
+        ),
+    },
+    Message {
+        code: 28,
+        en: (
+            "Kreta is unavailable",
+            "Kreta of the school keeps failing, retry after {retry_after} seconds",
+        ),
+        hu: (
+            "A Kréta nem elérhető",
+            "Az iskola Krétája folyamatosan hibázik, próbáld újra {retry_after} másodperc múlva",
+        ),
+    },
+    Message {
+        code: 29,
+        en: ("Kreta error response", "Kreta answered with an error"),
+        hu: ("Kréta hiba", "A Kréta hibával válaszolt"),
+    },
+    Message {
+        code: 30,
+        en: (
+            "Kreta didn't answer in time",
+            "Kreta didn't answer in time, try again later",
+        ),
+        hu: (
+            "A Kréta nem válaszolt időben",
+            "A Kréta nem válaszolt időben, próbáld újra később",
+        ),
+    },
+    Message {
+        code: 31,
+        en: (
+            "Unauthorized",
+            "Kreta rejected the access token, log in again",
+        ),
+        hu: (
+            "Lejárt bejelentkezés",
+            "A Kréta elutasította a hozzáférési tokent, jelentkezz be újra",
+        ),
+    },
+    Message {
+        code: 32,
+        en: (
+            "Invalid credentials",
+            "The username, password or refresh token is wrong",
+        ),
+        hu: (
+            "Hibás bejelentkezési adatok",
+            "Hibás felhasználónév, jelszó vagy frissítő token",
+        ),
+    },
+    Message {
+        code: 33,
+        en: (
+            "Invalid institute",
+            "The institute code is invalid or unknown",
+        ),
+        hu: (
+            "Érvénytelen intézmény",
+            "Az intézmény azonosítója érvénytelen vagy ismeretlen",
+        ),
+    },
+    Message {
+        code: 34,
+        en: (
+            "Student store is disabled",
+            "Changes can only be tracked with the student store enabled",
+        ),
+        hu: (
+            "A diák adatok tárolása ki van kapcsolva",
+            "A változások csak bekapcsolt tárolással követhetők",
+        ),
+    },
+    Message {
+        code: 35,
+        en: (
+            "Student store failed",
+            "The saved data of the student couldn't be used",
+        ),
+        hu: (
+            "Sikertelen tárolás",
+            "A diák elmentett adatai nem használhatók",
+        ),
+    },
+    Message {
+        code: 36,
+        en: (
+            "Subscriptions are disabled",
+            "This server doesn't send webhook notifications",
+        ),
+        hu: (
+            "A feliratkozás ki van kapcsolva",
+            "Ez a szerver nem küld webhook értesítéseket",
+        ),
+    },
+    Message {
+        code: 37,
+        en: (
+            "Subscription not found",
+            "The subscription is unknown or has been removed",
+        ),
+        hu: (
+            "A feliratkozás nem található",
+            "A feliratkozás ismeretlen vagy törölték",
+        ),
+    },
+    Message {
+        code: 38,
+        en: (
+            "Invalid webhook url",
+            "The webhook url must be an https url of a public host",
+        ),
+        hu: (
+            "Érvénytelen webhook cím",
+            "A webhook címnek egy nyilvános szerver https címének kell lennie",
+        ),
+    },
+];
+
+static UNKNOWN_ERROR: Message = Message {
+    code: 0,
+    en: ("Error", "Something went wrong"),
+    hu: ("Hiba", "Valami hiba történt"),
+};
+
+/// Returns the title and message of the error with `code` in `language`.
+pub fn localize(code: u32, language: Language) -> (&'static str, &'static str) {
+    let message = CATALOG
+        .iter()
+        .find(|message| message.code == code)
+        .unwrap_or(&UNKNOWN_ERROR);
+    match language {
+        Language::English => message.en,
+        Language::Hungarian => message.hu,
+    }
+}
+
+#[cfg(test)]
+mod messages_test {
+    use super::*;
+
+    #[test]
+    fn test_language_negotiation() {
+        assert_eq!(Language::from_accept_language(None), Language::English);
+        assert_eq!(
+            Language::from_accept_language(Some("hu-HU,hu;q=0.9,en-US;q=0.8")),
+            Language::Hungarian
+        );
+        assert_eq!(
+            Language::from_accept_language(Some("de, en;q=0.5, hu;q=0.7")),
+            Language::Hungarian
+        );
+        assert_eq!(
+            Language::from_accept_language(Some("de-DE")),
+            Language::English
+        );
+    }
+
+    #[test]
+    fn test_catalog_codes_are_unique() {
+        for (index, message) in CATALOG.iter().enumerate() {
+            assert!(CATALOG[index + 1..]
+                .iter()
+                .all(|other| other.code != message.code));
+        }
+        assert_eq!(
+            localize(32, Language::Hungarian).0,
+            "Hibás bejelentkezési adatok"
+        );
+        assert_eq!(localize(999, Language::English).0, "Error");
+    }
+}
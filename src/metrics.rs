@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use actix_web::dev::ResourceDef;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+
+use crate::institute;
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_http_requests_total",
+        "Number of handled requests by route and status code.",
+        &["route", "status"]
+    )
+    .unwrap()
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "kreta_proxy_http_request_duration_seconds",
+        "Time it took to answer a request by route.",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static UPSTREAM_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "kreta_proxy_upstream_request_duration_seconds",
+        "Time it took Kreta to answer by endpoint and institute.",
+        &["endpoint", "institute"]
+    )
+    .unwrap()
+});
+
+static UPSTREAM_RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_upstream_retries_total",
+        "Number of retried calls to Kreta by endpoint.",
+        &["endpoint"]
+    )
+    .unwrap()
+});
+
+static CIRCUITS_OPENED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_upstream_circuits_opened_total",
+        "Number of times the circuit of an institute was opened.",
+        &["institute"]
+    )
+    .unwrap()
+});
+
+static CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_cache_lookups_total",
+        "Number of response cache lookups by resource and result (hit or miss).",
+        &["resource", "result"]
+    )
+    .unwrap()
+});
+
+static WEBHOOK_DELIVERIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_webhook_deliveries_total",
+        "Number of webhook deliveries of subscriptions by result (success or failure).",
+        &["result"]
+    )
+    .unwrap()
+});
+
+static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "kreta_proxy_errors_total",
+        "Number of error responses by KretaError variant.",
+        &["variant"]
+    )
+    .unwrap()
+});
+
+/// Registers every metric, so they are exported before they are first used.
+pub fn init() {
+    Lazy::force(&HTTP_REQUESTS);
+    Lazy::force(&HTTP_REQUEST_DURATION);
+    Lazy::force(&UPSTREAM_REQUEST_DURATION);
+    Lazy::force(&UPSTREAM_RETRIES);
+    Lazy::force(&CIRCUITS_OPENED);
+    Lazy::force(&CACHE_LOOKUPS);
+    Lazy::force(&WEBHOOK_DELIVERIES);
+    Lazy::force(&ERRORS);
+}
+
+pub fn observe_request(route: &str, status: u16, elapsed: Duration) {
+    HTTP_REQUESTS
+        .with_label_values(&[route, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn observe_upstream(endpoint: &str, institute: &str, elapsed: Duration) {
+    UPSTREAM_REQUEST_DURATION
+        .with_label_values(&[endpoint, institute::metric_label(institute)])
+        .observe(elapsed.as_secs_f64());
+}
+
+pub fn count_upstream_retry(endpoint: &str) {
+    UPSTREAM_RETRIES.with_label_values(&[endpoint]).inc();
+}
+
+pub fn count_circuit_opened(institute: &str) {
+    CIRCUITS_OPENED
+        .with_label_values(&[institute::metric_label(institute)])
+        .inc();
+}
+
+pub fn count_cache_lookup(resource: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[resource, result]).inc();
+}
+
+pub fn count_webhook_delivery(success: bool) {
+    let result = if success { "success" } else { "failure" };
+    WEBHOOK_DELIVERIES.with_label_values(&[result]).inc();
+}
+
+pub fn count_error(variant: &str) {
+    ERRORS.with_label_values(&[variant]).inc();
+}
+
+/// Renders every registered metric in the Prometheus text format.
+pub fn render() -> (String, Vec<u8>) {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("Metrics couldn't be encoded: {}", err);
+    }
+    (encoder.format_type().to_string(), buffer)
+}
+
+/// The resource patterns registered in `main`. actix-web 2.0 doesn't tell
+/// which resource a request was routed to, so a route missing here is
+/// labelled `unmatched`.
+pub const ROUTES: &[&str] = &[
+    "/grades",
+    "/notes",
+    "/averages",
+    "/v2/schedules",
+    "/schedules",
+    "/schedules.ics",
+    "/tasks",
+    "/homework",
+    "/profile",
+    "/changes",
+    "/events/stream",
+    "/token",
+    "/feeds",
+    "/feeds/{feed_token}/assignments.ics",
+    "/feeds/{feed_token}",
+    "/subscriptions",
+    "/subscriptions/{subscription_id}",
+    "/healthz",
+    "/readyz",
+    "/version",
+    "/metrics",
+];
+
+static ROUTE_PATTERNS: Lazy<Vec<(&str, ResourceDef)>> = Lazy::new(|| {
+    ROUTES
+        .iter()
+        .map(|route| (*route, ResourceDef::new(*route)))
+        .collect()
+});
+
+/// The pattern of the resource matching a path, like `/feeds/{feed_token}`,
+/// so parameters like feed tokens never end up in the metrics. Paths no
+/// resource matches are `unmatched`. As it doesn't need the request to be
+/// routed, requests rejected before routing, like by the rate limiter, get
+/// their route too.
+pub fn route_label(path: &str) -> String {
+    ROUTE_PATTERNS
+        .iter()
+        .find(|(_, pattern)| pattern.is_match(path))
+        .map_or("unmatched", |(route, _)| *route)
+        .to_string()
+}
+
+#[cfg(test)]
+mod metrics_test {
+    use super::*;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/grades"), "/grades");
+        assert_eq!(route_label("/schedules.ics"), "/schedules.ics");
+        assert_eq!(
+            route_label("/feeds/s3cr3t/assignments.ics"),
+            "/feeds/{feed_token}/assignments.ics"
+        );
+        assert_eq!(route_label("/feeds/s3cr3t"), "/feeds/{feed_token}");
+        // Parameters equal to a fixed part of the path are no different
+        assert_eq!(
+            route_label("/feeds/feeds/assignments.ics"),
+            "/feeds/{feed_token}/assignments.ics"
+        );
+        assert_eq!(route_label("/feeds/s3cr3t/other"), "unmatched");
+        assert_eq!(route_label("/.env"), "unmatched");
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{ResourceDef, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{web, Error};
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use serde::Deserialize;
+
+use crate::config::{RateLimitConfig, RateLimitRule};
+use crate::error::KretaError;
+use crate::metrics;
+use crate::store::token_hash;
+
+/// Full buckets are dropped this often, they are the same as new ones.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn full(rule: &RateLimitRule, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(rule.burst),
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, rule: &RateLimitRule, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rule.per_second()).min(f64::from(rule.burst));
+        self.last_refill = now;
+    }
+
+    /// Takes a token from the bucket, or returns how long to wait for one.
+    fn take(&mut self, rule: &RateLimitRule, now: Instant) -> Result<(), Duration> {
+        self.refill(rule, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(Duration::from_secs_f64(missing / rule.per_second()))
+        }
+    }
+
+    fn is_full(&self, rule: &RateLimitRule, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * rule.per_second() >= f64::from(rule.burst)
+    }
+}
+
+/// Token buckets per route for every client ip and every Kreta token of an
+/// institute, shared by the workers of the server.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// The routes with their own rule, matched before requests are routed.
+    routes: Vec<(String, ResourceDef)>,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+}
+
+#[derive(Deserialize)]
+struct RateLimitQuery {
+    url: Option<String>,
+    token: Option<String>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let routes = config
+            .routes
+            .keys()
+            .map(|route| (route.clone(), ResourceDef::new(route)))
+            .collect();
+        RateLimiter {
+            config,
+            routes,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The route of the request the buckets belong to: the pattern of a route
+    /// with its own rule, otherwise the pattern of the resource it asks for.
+    fn route(&self, req: &ServiceRequest) -> String {
+        let path = req.path();
+        match self
+            .routes
+            .iter()
+            .find(|(_, pattern)| pattern.is_match(path))
+        {
+            Some((route, _)) => route.clone(),
+            None => metrics::route_label(path),
+        }
+    }
+
+    fn rule(&self, route: &str) -> &RateLimitRule {
+        self.config
+            .routes
+            .get(route)
+            .unwrap_or(&self.config.default)
+    }
+
+    /// Takes a token from every bucket `keys` belong to on `route`,
+    /// returning the longest wait when any of them is empty.
+    fn check(&self, route: &str, keys: Vec<String>) -> Result<(), Duration> {
+        let rule = self.rule(route);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut retry_after: Option<Duration> = None;
+        for key in keys {
+            let bucket = buckets
+                .entry((route.to_string(), key))
+                .or_insert_with(|| Bucket::full(rule, now));
+            if let Err(wait) = bucket.take(rule, now) {
+                retry_after = Some(retry_after.map_or(wait, |longest| longest.max(wait)));
+            }
+        }
+
+        match retry_after {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+
+    fn prune(&self) {
+        let now = Instant::now();
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|(route, _), bucket| !bucket.is_full(self.rule(route), now));
+    }
+
+    fn keys(&self, req: &ServiceRequest) -> Vec<String> {
+        let mut keys = Vec::new();
+
+        let forwarded_for = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .filter(|_| self.config.trust_forwarded_for);
+        let client_ip = match forwarded_for {
+            Some(forwarded_for) => Some(client_ip(forwarded_for)),
+            None => req.peer_addr().map(|addr| addr.ip().to_string()),
+        };
+        if let Some(client_ip) = client_ip {
+            keys.push(format!("ip:{}", client_ip));
+        }
+
+        if let Ok(query) = web::Query::<RateLimitQuery>::from_query(req.query_string()) {
+            if let Some(token) = &query.token {
+                keys.push(format!(
+                    "token:{}:{}",
+                    query.url.as_deref().unwrap_or("-"),
+                    token_hash(token)
+                ));
+            }
+        }
+
+        keys
+    }
+}
+
+/// Drops the buckets of clients which haven't been seen for a while.
+pub async fn prune(limiter: Arc<RateLimiter>) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        limiter.prune();
+    }
+}
+
+/// The client address in `X-Forwarded-For`: the last one, as that is the one
+/// added by the trusted proxy, the others are sent by the client.
+fn client_ip(forwarded_for: &str) -> String {
+    let client_ip = forwarded_for.rsplit(',').next().unwrap_or("").trim();
+    client_ip
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|_err| client_ip.to_string())
+}
+
+/// Middleware answering with 429 once a client runs out of requests on a route.
+pub struct RateLimit(pub Arc<RateLimiter>);
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service,
+            limiter: self.0.clone(),
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Either<S::Future, LocalBoxFuture<'static, Result<Self::Response, Self::Error>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !self.limiter.config.enabled {
+            return Either::Left(self.service.call(req));
+        }
+
+        let route = self.limiter.route(&req);
+        let keys = self.limiter.keys(&req);
+
+        match self.limiter.check(&route, keys) {
+            Ok(()) => Either::Left(self.service.call(req)),
+            Err(retry_after) => {
+                let retry_after_secs = retry_after.as_secs() + 1;
+                // Built when polled, inside the request context of `AccessLog`,
+                // so the answer is localized and carries the request id
+                Either::Right(Box::pin(async move {
+                    Ok(req.error_response(KretaError::RateLimited(retry_after_secs)))
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod rate_limit_test {
+    use super::*;
+    use crate::access_log::AccessLog;
+    use crate::messages::{localize, Language};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use serde_json::Value;
+
+    fn rule() -> RateLimitRule {
+        RateLimitRule {
+            burst: 2,
+            per_minute: 60,
+        }
+    }
+
+    #[test]
+    fn test_bucket_runs_out_and_refills() {
+        let rule = rule();
+        let start = Instant::now();
+        let mut bucket = Bucket::full(&rule, start);
+
+        assert!(bucket.take(&rule, start).is_ok());
+        assert!(bucket.take(&rule, start).is_ok());
+        let wait = bucket.take(&rule, start).unwrap_err();
+        assert!(wait <= Duration::from_secs(1));
+
+        let later = start + Duration::from_millis(1500);
+        assert!(bucket.take(&rule, later).is_ok());
+        assert!(bucket.take(&rule, later).is_err());
+        assert!(bucket.is_full(&rule, later + Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_keys_are_limited_separately() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            default: rule(),
+            ..RateLimitConfig::default()
+        });
+
+        assert!(limiter.check("/grades", vec![String::from("ip:a")]).is_ok());
+        assert!(limiter.check("/grades", vec![String::from("ip:a")]).is_ok());
+        assert!(limiter
+            .check("/grades", vec![String::from("ip:a")])
+            .is_err());
+        assert!(limiter.check("/grades", vec![String::from("ip:b")]).is_ok());
+        assert!(limiter
+            .check("/profile", vec![String::from("ip:a")])
+            .is_ok());
+
+        limiter.prune();
+        assert!(limiter
+            .check("/grades", vec![String::from("ip:a")])
+            .is_err());
+
+        let with_token = |ip: &str| vec![String::from(ip), String::from("token:x")];
+        assert!(limiter.check("/tasks", with_token("ip:c")).is_ok());
+        assert!(limiter.check("/tasks", with_token("ip:d")).is_ok());
+        assert!(limiter.check("/tasks", with_token("ip:e")).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_rejection_is_localized() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            default: rule(),
+            ..RateLimitConfig::default()
+        });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RateLimit(Arc::new(limiter)))
+                .wrap(AccessLog)
+                .route(
+                    "/grades",
+                    web::get().to(|| async { HttpResponse::Ok().finish() }),
+                ),
+        )
+        .await;
+        let request = || {
+            TestRequest::get()
+                .uri("/grades")
+                .peer_addr("203.0.113.7:5123".parse().unwrap())
+                .header("Accept-Language", "hu")
+                .header("X-Request-Id", "rate-limited")
+                .to_request()
+        };
+
+        for _ in 0..2 {
+            let res = test::call_service(&mut app, request()).await;
+            assert!(res.status().is_success());
+        }
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status().as_u16(), 429);
+        let body: Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["title"], localize(25, Language::Hungarian).0);
+        assert_eq!(body["requestId"], "rate-limited");
+    }
+
+    #[test]
+    fn test_client_ip_is_the_last_forwarded_one() {
+        assert_eq!(client_ip("203.0.113.7"), "203.0.113.7");
+        assert_eq!(client_ip("1.2.3.4, 203.0.113.7"), "203.0.113.7");
+        assert_eq!(client_ip("1.2.3.4,203.0.113.7:5123"), "203.0.113.7");
+    }
+}
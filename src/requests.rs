@@ -1,51 +1,174 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+
 use reqwest::header::{HeaderMap, HeaderValue};
 
+use crate::cache::{CacheKey, Resource, ResponseCache};
+use crate::config;
+use crate::context::{self, REQUEST_ID_HEADER};
+use crate::error::UpstreamDetails;
+use crate::institute::InstituteCode;
+use crate::metrics;
+use crate::upstream;
 use crate::*;
 
 static HEADER: &str = "Kreta";
+static CLIENT_ID: &str = "919e0c1c-76a2-4646-a2fb-7085bbbf3c56";
+
+static RESPONSES: Lazy<ResponseCache<Arc<UpstreamBody>>> =
+    Lazy::new(|| ResponseCache::new(config::get().cache.max_entries));
+
+/// A successful answer of Kreta.
+pub struct UpstreamBody {
+    status: u16,
+    path: String,
+    body: String,
+}
+
+fn client() -> reqwest::Client {
+    let upstream = &config::get().upstream;
+    reqwest::Client::builder()
+        .connect_timeout(upstream.connect_timeout())
+        .timeout(upstream.timeout())
+        .build()
+        .unwrap_or_else(|_err| reqwest::Client::new())
+}
+
+fn institute_url(url: &InstituteCode, path: &str) -> String {
+    format!(
+        "{}{}",
+        config::get().upstream.institute_url(url.as_str()),
+        path
+    )
+}
 
 pub async fn create_token(
-    url: &str,
+    url: &InstituteCode,
     username: &str,
     password: &str,
 ) -> Result<Authentication, KretaError> {
-    let body = format!("institute_code={}&userName={}&password={}&grant_type=password&client_id=919e0c1c-76a2-4646-a2fb-7085bbbf3c56", url, username, password);
+    let body = format!(
+        "institute_code={}&userName={}&password={}&grant_type=password&client_id={}",
+        url, username, password, CLIENT_ID
+    );
 
-    let url = format!("https://{}.e-kreta.hu/idp/api/v1/Token", url);
-    let client = reqwest::Client::new();
+    let request_url = institute_url(url, "/idp/api/v1/Token");
+    let client = client();
 
     let resp: Authentication = parse_body(
-        client
-            .get(&url)
-            .header("User-Agent", HEADER)
-            .body(body)
-            .send()
-            .await,
+        send(
+            "Token",
+            url.as_str(),
+            client
+                .get(&request_url)
+                .header("User-Agent", HEADER)
+                .body(body),
+        )
+        .await,
     )
-    .await?;
+    .await
+    .map_err(invalid_grant)?;
     Ok(resp)
 }
 
+/// Logs in with a refresh token. Kreta rotates refresh tokens on every use:
+/// the returned one replaces `refresh_token`, which stops working. Feeds,
+/// subscriptions and event streams keeping a refresh token therefore need
+/// one of their own login, and must save the new token after every refresh.
+pub async fn refresh_access_token(
+    url: &InstituteCode,
+    refresh_token: &str,
+) -> Result<Authentication, KretaError> {
+    let body = format!(
+        "institute_code={}&refresh_token={}&grant_type=refresh_token&client_id={}",
+        url, refresh_token, CLIENT_ID
+    );
+
+    let request_url = institute_url(url, "/idp/api/v1/Token");
+    let client = client();
+
+    let resp: Authentication = parse_body(
+        send(
+            "Token",
+            url.as_str(),
+            client
+                .get(&request_url)
+                .header("User-Agent", HEADER)
+                .body(body),
+        )
+        .await,
+    )
+    .await
+    .map_err(invalid_grant)?;
+    Ok(resp)
+}
+
+/// Tells refused credentials apart from other errors of the token endpoint.
+fn invalid_grant(err: KretaError) -> KretaError {
+    match err {
+        KretaError::ErrorResponse(_, response) if response.error == "invalid_grant" => {
+            KretaError::InvalidGrant(response)
+        }
+        err => err,
+    }
+}
+
 pub async fn get_schools() -> Result<Vec<School>, KretaError> {
+    let upstream = &config::get().upstream;
+
     let mut headers = HeaderMap::new();
-    headers.append(
-        "apiKey",
-        HeaderValue::from_static("7856d350-1fda-45f5-822d-e1a2f3f1acf0"),
-    );
+    if let Ok(api_key) = HeaderValue::from_str(&upstream.global_api_key) {
+        headers.append("apiKey", api_key);
+    }
     headers.append("User-Agent", HeaderValue::from_static(HEADER));
 
-    let client = reqwest::Client::new();
+    let client = client();
 
     let request = client
-        .get("https://kretaglobalmobileapi.ekreta.hu/api/v1/Institute")
+        .get(&format!("{}/api/v1/Institute", upstream.global_api_url))
         .headers(headers);
 
-    let schools: Vec<School> = parse_body(request.send().await).await?;
+    let schools: Vec<School> =
+        parse_body(send_idempotent("Institute", "global", request).await).await?;
 
     Ok(schools)
 }
 
-pub async fn get_homework(token: String, url: String) -> Result<Vec<Homework>, KretaError> {
+/// Checks whether the global Kreta api answers within `timeout`,
+/// the status code of the answer doesn't matter.
+pub async fn probe_global_api(timeout: Duration) -> Result<(), reqwest::Error> {
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+
+    client
+        .head(&config::get().upstream.global_api_url)
+        .header("User-Agent", HEADER)
+        .send()
+        .await?;
+    Ok(())
+}
+
+pub async fn get_homework(token: String, url: &InstituteCode) -> Result<Vec<Homework>, KretaError> {
+    fetch_homework(token, url, true).await
+}
+
+/// Like `get_homework`, but fails when any homework couldn't be fetched
+/// instead of leaving it out, so change detection doesn't take it for removed.
+pub async fn get_every_homework(
+    token: String,
+    url: &InstituteCode,
+) -> Result<Vec<Homework>, KretaError> {
+    fetch_homework(token, url, false).await
+}
+
+async fn fetch_homework(
+    token: String,
+    url: &InstituteCode,
+    skip_failed: bool,
+) -> Result<Vec<Homework>, KretaError> {
     let now: Date<_> = Utc::now().date();
     let last_month = if now.month() == 1 {
         now.with_month(12)
@@ -58,34 +181,26 @@ pub async fn get_homework(token: String, url: String) -> Result<Vec<Homework>, K
 
     let schedules = get_schedule(
         token.clone(),
-        url.clone(),
+        url,
         last_month.format("%Y-%m-%d").to_string(),
         now.format("%Y-%m-%d").to_string(),
     )
     .await?;
 
-    let client = reqwest::Client::new();
-
     let mut homework: Vec<Homework> = Vec::new();
     let mut unrefined_homework: Vec<UnrefinedHomework> = Vec::new();
     for schedule in schedules {
         match schedule.homework_id {
             Some(id) => {
-                let url = format!(
-                    "https://{}.e-kreta.hu/mapi/api/v1/HaziFeladat/TanarHaziFeladat/{}",
-                    url, id
+                let request_url = institute_url(
+                    url,
+                    &format!("/mapi/api/v1/HaziFeladat/TanarHaziFeladat/{}", id),
                 );
-                let resp: Result<UnrefinedHomework, KretaError> = parse_body(
-                    client
-                        .get(&url)
-                        .header("User-Agent", HEADER)
-                        .bearer_auth(&token)
-                        .send()
-                        .await,
-                )
-                .await;
+                let resp: Result<UnrefinedHomework, KretaError> =
+                    fetch(Resource::Homework, "HaziFeladat", url, &token, &request_url).await;
                 match resp {
                     Ok(hw) => unrefined_homework.push(hw),
+                    Err(err) if !skip_failed => return Err(err),
                     Err(_) => {}
                 };
             }
@@ -100,7 +215,7 @@ pub async fn get_homework(token: String, url: String) -> Result<Vec<Homework>, K
 
 pub async fn get_schedule_v2(
     token: String,
-    url: String,
+    url: &InstituteCode,
     from_date: String,
     to_date: String,
 ) -> Result<BTreeMap<String, Vec<Lesson>>, KretaError> {
@@ -119,25 +234,19 @@ pub async fn get_schedule_v2(
 
 pub async fn get_schedule(
     token: String,
-    url: String,
+    url: &InstituteCode,
     from_date: String,
     to_date: String,
 ) -> Result<Vec<Lesson>, KretaError> {
-    let url = format!(
-        "https://{}.e-kreta.hu/mapi/api/v1/Lesson?fromDate={}&toDate={}",
-        url, from_date, to_date
+    let request_url = institute_url(
+        url,
+        &format!(
+            "/mapi/api/v1/Lesson?fromDate={}&toDate={}",
+            from_date, to_date
+        ),
     );
-    let client = reqwest::Client::new();
-
-    let resp: Vec<UnrefinedLesson> = parse_body(
-        client
-            .get(&url)
-            .header("User-Agent", HEADER)
-            .bearer_auth(token)
-            .send()
-            .await,
-    )
-    .await?;
+    let resp: Vec<UnrefinedLesson> =
+        fetch(Resource::Schedule, "Lesson", url, &token, &request_url).await?;
 
     let mut lessons: Vec<Lesson> = Vec::new();
 
@@ -151,12 +260,12 @@ pub async fn get_schedule(
 
 pub async fn get_grades(
     token: &str,
-    url: &str,
+    url: &InstituteCode,
 ) -> Result<BTreeMap<String, Vec<Grade>>, KretaError> {
     let mut grades: BTreeMap<String, Vec<Grade>> = BTreeMap::new();
     let mut subjects: Vec<String> = Vec::new();
 
-    let profile = get_profile(token, &url).await?.refine();
+    let profile = get_profile(token, url).await?.refine();
 
     for grade in profile.grades {
         let vec = grades.entry(grade.subject.clone()).or_insert(Vec::new());
@@ -172,52 +281,44 @@ pub async fn get_grades(
     Ok(grades)
 }
 
-pub async fn get_notes(token: &str, url: &str) -> Result<Vec<Note>, KretaError> {
-    let profile = get_profile(token, &url).await?.refine();
+pub async fn get_notes(token: &str, url: &InstituteCode) -> Result<Vec<Note>, KretaError> {
+    let profile = get_profile(token, url).await?.refine();
     Ok(profile.notes)
 }
 
-pub async fn get_averages(token: &str, url: &str) -> Result<Vec<Average>, KretaError> {
-    let profile = get_profile(token, &url).await?.refine();
+pub async fn get_averages(token: &str, url: &InstituteCode) -> Result<Vec<Average>, KretaError> {
+    let profile = get_profile(token, url).await?.refine();
     Ok(profile.averages)
 }
 
-pub async fn get_profile(token: &str, url: &str) -> Result<UnrefinedProfile, KretaError> {
-    let url = format!("https://{}.e-kreta.hu/mapi/api/v1/Student", url);
-    let client = reqwest::Client::new();
-    let profile: UnrefinedProfile = parse_body(
-        client
-            .get(&url)
-            .header("User-Agent", HEADER)
-            .bearer_auth(token)
-            .send()
-            .await,
-    )
-    .await?;
+pub async fn get_profile(token: &str, url: &InstituteCode) -> Result<UnrefinedProfile, KretaError> {
+    let request_url = institute_url(url, "/mapi/api/v1/Student");
+    let profile: UnrefinedProfile =
+        fetch(Resource::Profile, "Student", url, token, &request_url).await?;
     return Ok(profile);
 }
 
 pub async fn get_tasks(
     token: &str,
-    url: &str,
+    url: &InstituteCode,
     from_date: &str,
     to_date: &str,
 ) -> Result<Vec<Task>, KretaError> {
-    let url = format!(
-        "https://{}.e-kreta.hu/mapi/api/v1/BejelentettSzamonkeres?DatumTol={}&DatumIg={}",
-        url, from_date, to_date
+    let request_url = institute_url(
+        url,
+        &format!(
+            "/mapi/api/v1/BejelentettSzamonkeres?DatumTol={}&DatumIg={}",
+            from_date, to_date
+        ),
     );
-    let client = reqwest::Client::new();
-
     let mut tasks: Vec<Task> = Vec::new();
 
-    let resp: Vec<UnrefinedTask> = parse_body(
-        client
-            .get(&url)
-            .header("User-Agent", HEADER)
-            .bearer_auth(token)
-            .send()
-            .await,
+    let resp: Vec<UnrefinedTask> = fetch(
+        Resource::Tasks,
+        "BejelentettSzamonkeres",
+        url,
+        token,
+        &request_url,
     )
     .await?;
 
@@ -228,30 +329,170 @@ pub async fn get_tasks(
     Ok(tasks)
 }
 
-async fn parse_body<T>(result: Result<reqwest::Response, reqwest::Error>) -> Result<T, KretaError>
+/// Sends the request to Kreta once the circuit of `institute` and the
+/// concurrency limits allow it, recording how long `endpoint` took to answer.
+async fn send(
+    endpoint: &'static str,
+    institute: &str,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, KretaError> {
+    upstream::check_circuit(institute)?;
+    let _permit = upstream::acquire(institute).await?;
+
+    let request = match context::request_id() {
+        Some(request_id) => request.header(REQUEST_ID_HEADER, request_id),
+        None => request,
+    };
+
+    let request_started = Instant::now();
+    let response = request.send().await;
+    let elapsed = request_started.elapsed();
+    metrics::observe_upstream(endpoint, institute, elapsed);
+    context::add_upstream_time(elapsed);
+    upstream::record_result(
+        institute,
+        match &response {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_err) => false,
+        },
+    );
+    response.map_err(KretaError::KretaRequestSendFailed)
+}
+
+/// Sends a request which is safe to repeat, retrying transport errors and
+/// temporary failures of Kreta with jittered exponential backoff.
+async fn send_idempotent(
+    endpoint: &'static str,
+    institute: &str,
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, KretaError> {
+    let upstream_config = &config::get().upstream;
+    let mut attempt = 0;
+
+    loop {
+        let attempt_request = match request.try_clone() {
+            Some(attempt_request) => attempt_request,
+            None => return send(endpoint, institute, request).await,
+        };
+        let result = send(endpoint, institute, attempt_request).await;
+        attempt += 1;
+
+        let (reason, retry_after) = match &result {
+            Ok(response) if upstream::is_retryable(response.status()) => (
+                response.status().to_string(),
+                upstream::retry_after(response.headers()),
+            ),
+            Err(KretaError::KretaRequestSendFailed(err)) => (err.to_string(), None),
+            _ => return result,
+        };
+        if attempt > upstream_config.max_retries {
+            return result;
+        }
+        let delay = match upstream::retry_delay(upstream_config, attempt, retry_after) {
+            Some(delay) => delay,
+            None => return result,
+        };
+
+        metrics::count_upstream_retry(endpoint);
+        context::add_upstream_retry();
+        warn!(
+            "Retrying {} of {} in {}ms after attempt {} failed: {}",
+            endpoint,
+            institute,
+            delay.as_millis(),
+            attempt,
+            reason
+        );
+        tokio::time::delay_for(delay).await;
+    }
+}
+
+/// Fetches the data at `request_url` with the token of the student,
+/// answering from the response cache while it is fresh.
+async fn fetch<T>(
+    resource: Resource,
+    endpoint: &'static str,
+    url: &InstituteCode,
+    token: &str,
+    request_url: &str,
+) -> Result<T, KretaError>
+where
+    T: DeserializeOwned,
+{
+    let key = CacheKey::new(token, endpoint, request_url);
+    if let Some(body) = RESPONSES.get(resource, &key) {
+        return parse_json(&body);
+    }
+
+    let client = client();
+    let body = read_body(
+        send_idempotent(
+            endpoint,
+            url.as_str(),
+            client
+                .get(request_url)
+                .header("User-Agent", HEADER)
+                .bearer_auth(token),
+        )
+        .await,
+    )
+    .await?;
+
+    // Only answers which could be parsed are cached
+    let parsed = parse_json(&body)?;
+    RESPONSES.insert(resource, key, Arc::new(body));
+    Ok(parsed)
+}
+
+async fn parse_body<T>(result: Result<reqwest::Response, KretaError>) -> Result<T, KretaError>
 where
-    T: serde::de::DeserializeOwned,
+    T: DeserializeOwned,
 {
-    match result {
-        Err(err) => Err(KretaError::KretaRequestSendFailed(err)),
-        Ok(response) => {
-            let status_code: reqwest::StatusCode = response.status();
-            if status_code.is_success() {
-                response
-                    .json()
-                    .await
-                    .map_err(|err| KretaError::ParseError(err))
-            } else {
-                let error = response
-                    .json()
-                    .await
-                    .map_err(|err| KretaError::KretaBadResponse(err))?;
-                Err(KretaError::ErrorResponse(error))
-            }
+    parse_json(&read_body(result).await?)
+}
+
+/// Reads the answer of Kreta, keeping the status, path and the start of the
+/// body of error answers which can't be understood.
+async fn read_body(
+    result: Result<reqwest::Response, KretaError>,
+) -> Result<UpstreamBody, KretaError> {
+    let response = result?;
+    let status_code: reqwest::StatusCode = response.status();
+    let path = response.url().path().to_string();
+    let body = response
+        .text()
+        .await
+        .map_err(KretaError::KretaRequestSendFailed)?;
+
+    if status_code.is_success() {
+        return Ok(UpstreamBody {
+            status: status_code.as_u16(),
+            path,
+            body,
+        });
+    }
+
+    match serde_json::from_str::<KretaErrorResponse>(&body) {
+        Ok(error) => Err(KretaError::ErrorResponse(status_code.as_u16(), error)),
+        Err(_err) => {
+            let details = UpstreamDetails::new(status_code.as_u16(), path, &body);
+            warn!("Kreta answered with an unrecognisable error: {}", details);
+            Err(KretaError::KretaBadResponse(details))
         }
     }
 }
 
+fn parse_json<T>(body: &UpstreamBody) -> Result<T, KretaError>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str(&body.body).map_err(|err| {
+        let details = UpstreamDetails::new(body.status, body.path.clone(), &body.body);
+        warn!("Kreta answer couldn't be parsed: {};error={}", details, err);
+        KretaError::ParseError(details, err.to_string())
+    })
+}
+
 #[cfg(test)]
 mod requests_integration_test {
     use super::*;
@@ -270,9 +511,9 @@ mod requests_integration_test {
         }
     }
 
-    fn get_url() -> String {
+    fn get_url() -> InstituteCode {
         match std::env::var("SCHOOL_URL") {
-            Ok(school_url) => school_url,
+            Ok(school_url) => InstituteCode::parse(&school_url).unwrap(),
             Err(_err) => panic!("School url not specified!"),
         }
     }
@@ -288,7 +529,7 @@ mod requests_integration_test {
     async fn test_schedules() {
         let schedules = get_schedule(
             get_token().await,
-            get_url(),
+            &get_url(),
             String::from("2020-01-05"),
             String::from("2020-01-12"),
         )
@@ -300,7 +541,7 @@ mod requests_integration_test {
     async fn test_schedules_v2() {
         let schedules = get_schedule_v2(
             get_token().await,
-            get_url(),
+            &get_url(),
             String::from("2020-01-05"),
             String::from("2020-01-12"),
         )
@@ -340,7 +581,7 @@ mod requests_integration_test {
 
     #[tokio::test]
     async fn test_homework() {
-        let homework = get_homework(get_token().await, get_url()).await;
+        let homework = get_homework(get_token().await, &get_url()).await;
         assert!(&homework.is_ok(), homework);
     }
 }
@@ -13,14 +13,14 @@ pub struct Authentication {
     pub access_token: String,
     token_type: String,
     expires_in: u16,
-    refresh_token: String,
+    pub refresh_token: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct School {
     institute_id: u32,
-    institute_code: String,
+    pub institute_code: String,
     name: String,
     url: String,
     city: String,
@@ -45,17 +45,17 @@ pub struct UnrefinedLesson {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Lesson {
-    subject: String,
+    pub subject: String,
     pub date: String,
-    start_of_class: String,
-    end_of_class: String,
-    period_number: i8,
-    cancelled: bool,
-    stand_in: bool,
-    class_name: String,
-    teacher: String,
-    room: String,
-    topic: String,
+    pub start_of_class: String,
+    pub end_of_class: String,
+    pub period_number: i8,
+    pub cancelled: bool,
+    pub stand_in: bool,
+    pub class_name: String,
+    pub teacher: String,
+    pub room: String,
+    pub topic: String,
     pub homework_id: Option<i64>,
 }
 
@@ -123,7 +123,7 @@ pub struct UnrefinedProfile {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Profile {
-    id: u64,
+    pub id: u64,
     name: String,
     school_name: String,
     pub grades: Vec<Grade>,
@@ -335,13 +335,13 @@ pub struct UnrefinedTask {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Task {
-    id: i64,
-    subject: String,
-    topic: String,
-    grade_type: String,
-    teacher: String,
-    due_date: String,
-    creation_date: String,
+    pub id: i64,
+    pub subject: String,
+    pub topic: String,
+    pub grade_type: String,
+    pub teacher: String,
+    pub due_date: String,
+    pub creation_date: String,
 }
 
 impl UnrefinedTask {
@@ -379,12 +379,12 @@ pub struct UnrefinedHomework {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Homework {
-    id: i64,
-    subject: String,
-    content: String,
-    teacher: String,
-    due_date: String,
-    creation_date: String,
+    pub id: i64,
+    pub subject: String,
+    pub content: String,
+    pub teacher: String,
+    pub due_date: String,
+    pub creation_date: String,
 }
 
 impl UnrefinedHomework {
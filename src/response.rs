@@ -0,0 +1,318 @@
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use serde::{forward_to_deserialize_any, Serialize};
+use serde_json::Value;
+
+use crate::error::KretaError;
+
+/// Byte order mark, so spreadsheet applications detect the encoding of the
+/// accented hungarian texts correctly.
+static UTF8_BOM: &str = "\u{feff}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    Csv,
+}
+
+/// Formats every response type can be serialized to.
+pub static DOCUMENT_FORMATS: &[Format] = &[Format::Json, Format::MessagePack, Format::Cbor];
+
+/// Formats available for responses which are lists of flat records.
+pub static TABULAR_FORMATS: &[Format] =
+    &[Format::Json, Format::MessagePack, Format::Cbor, Format::Csv];
+
+impl Format {
+    fn from_name(name: &str) -> Option<Format> {
+        match name.trim().to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "msgpack" => Some(Format::MessagePack),
+            "cbor" => Some(Format::Cbor),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type.trim().to_lowercase().as_str() {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" => Some(Format::MessagePack),
+            "application/cbor" => Some(Format::Cbor),
+            "text/csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// Picks one of the `supported` formats from the `format` query parameter
+    /// if present, otherwise from the `Accept` header, defaulting to JSON.
+    pub fn negotiate(req: &HttpRequest, requested: Option<&str>, supported: &[Format]) -> Format {
+        if let Some(format) = requested
+            .and_then(Format::from_name)
+            .filter(|format| supported.contains(format))
+        {
+            return format;
+        }
+
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(|accept| {
+                by_quality(accept)
+                    .into_iter()
+                    .filter_map(|media_type| Format::from_media_type(&media_type))
+                    .find(|format| supported.contains(format))
+            })
+            .unwrap_or(Format::Json)
+    }
+
+    /// Serializes `value` into a successful response. CSV needs a list of
+    /// records, use `csv_response` for that.
+    pub fn respond<T: Serialize>(self, value: &T) -> Result<HttpResponse, KretaError> {
+        let (content_type, body) = match self {
+            Format::MessagePack => (
+                "application/msgpack",
+                rmp_serde::to_vec_named(value)
+                    .map_err(|err| KretaError::SerializationError(format!("{}", err)))?,
+            ),
+            Format::Cbor => (
+                "application/cbor",
+                serde_cbor::to_vec(value)
+                    .map_err(|err| KretaError::SerializationError(format!("{}", err)))?,
+            ),
+            Format::Json | Format::Csv => (
+                "application/json",
+                serde_json::to_vec(value)
+                    .map_err(|err| KretaError::SerializationError(format!("{}", err)))?,
+            ),
+        };
+
+        Ok(HttpResponse::build(StatusCode::OK)
+            .content_type(content_type)
+            .header(header::VARY, "Accept")
+            .body(body))
+    }
+}
+
+/// Returns the values of an `Accept` style header, like the media types of
+/// `Accept` or the languages of `Accept-Language`, ordered by their quality,
+/// leaving out the ones which are explicitly not acceptable.
+pub fn by_quality(accept: &str) -> Vec<String> {
+    let mut media_types: Vec<(String, f32)> = accept
+        .split(',')
+        .map(|entry| {
+            let mut parts = entry.split(';');
+            let media_type = parts.next().unwrap_or("").trim().to_string();
+            let quality = parts
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            (media_type, quality)
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+
+    // The sort is stable so equally preferred types keep the client's order
+    media_types.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    media_types
+        .into_iter()
+        .map(|(media_type, _)| media_type)
+        .collect()
+}
+
+/// Writes the records as a CSV attachment with a header row made of
+/// the serialized field names of `T`.
+pub fn csv_response<'a, T, I>(name: &str, records: I) -> Result<HttpResponse, KretaError>
+where
+    T: Serialize + DeserializeOwned + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    let body = write_csv(records)?;
+
+    Ok(HttpResponse::build(StatusCode::OK)
+        .content_type("text/csv; charset=utf-8")
+        .header(header::VARY, "Accept")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.csv\"", name),
+        )
+        .body(body))
+}
+
+/// The header is written even without records, its names are taken from
+/// the fields `T` deserializes, which the resources name like they serialize.
+fn write_csv<'a, T, I>(records: I) -> Result<Vec<u8>, KretaError>
+where
+    T: Serialize + DeserializeOwned + 'a,
+    I: IntoIterator<Item = &'a T>,
+{
+    let serialization_error =
+        |err: &dyn std::fmt::Display| KretaError::SerializationError(format!("{}", err));
+    let fields = field_names::<T>()
+        .ok_or_else(|| serialization_error(&"Only structs can be written as CSV"))?;
+
+    let mut writer = csv::Writer::from_writer(UTF8_BOM.as_bytes().to_vec());
+    writer
+        .write_record(fields)
+        .map_err(|err| serialization_error(&err))?;
+    for record in records {
+        let record = serde_json::to_value(record).map_err(|err| serialization_error(&err))?;
+        writer
+            .write_record(fields.iter().map(|field| cell(record.get(field))))
+            .map_err(|err| serialization_error(&err))?;
+    }
+    writer.into_inner().map_err(|err| serialization_error(&err))
+}
+
+/// A field as a CSV cell. Texts starting like a formula are prefixed with an
+/// apostrophe, so spreadsheets show them instead of evaluating them.
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(text)) if text.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) => {
+            format!("'{}", text)
+        }
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn field_names<T: DeserializeOwned>() -> Option<&'static [&'static str]> {
+    let mut fields = None;
+    // Always fails, the names are collected before that
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// A deserializer which only notes the field names of the struct asking
+/// for them.
+struct FieldNames<'a>(&'a mut Option<&'static [&'static str]>);
+
+impl<'de, 'a> Deserializer<'de> for FieldNames<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(fields);
+        Err(de::Error::custom("field names collected"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod response_test {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde::Deserialize;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Record {
+        subject: String,
+        grade_type: String,
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let req = TestRequest::with_header("Accept", "text/csv;q=0.5, application/json")
+            .to_http_request();
+        assert_eq!(Format::negotiate(&req, None, TABULAR_FORMATS), Format::Json);
+        assert_eq!(
+            Format::negotiate(&req, Some("csv"), TABULAR_FORMATS),
+            Format::Csv
+        );
+
+        let req = TestRequest::with_header("Accept", "text/html, text/csv").to_http_request();
+        assert_eq!(Format::negotiate(&req, None, TABULAR_FORMATS), Format::Csv);
+        assert_eq!(
+            Format::negotiate(&req, None, DOCUMENT_FORMATS),
+            Format::Json
+        );
+
+        let req =
+            TestRequest::with_header("Accept", "text/csv, application/cbor").to_http_request();
+        assert_eq!(
+            Format::negotiate(&req, None, DOCUMENT_FORMATS),
+            Format::Cbor
+        );
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            Format::negotiate(&req, Some("msgpack"), DOCUMENT_FORMATS),
+            Format::MessagePack
+        );
+        assert_eq!(
+            Format::negotiate(&req, Some("xml"), DOCUMENT_FORMATS),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let records = vec![Record {
+            subject: String::from("Magyar nyelv, irodalom"),
+            grade_type: String::from("\"Témazáró\""),
+        }];
+        let written = String::from_utf8(write_csv(&records).unwrap()).unwrap();
+
+        assert_eq!(
+            written,
+            "\u{feff}subject,gradeType\n\"Magyar nyelv, irodalom\",\"\"\"Témazáró\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_write_empty_csv() {
+        let records: Vec<Record> = Vec::new();
+        let written = String::from_utf8(write_csv(&records).unwrap()).unwrap();
+
+        assert_eq!(written, "\u{feff}subject,gradeType\n");
+    }
+
+    #[test]
+    fn test_formulas_are_neutralized() {
+        let records: Vec<Record> = [
+            "=HYPERLINK(\"http://evil\")",
+            "+1",
+            "-1",
+            "@SUM(A1)",
+            "\t=1",
+            "\r=1",
+            "ok",
+        ]
+        .iter()
+        .map(|text| Record {
+            subject: text.to_string(),
+            grade_type: String::new(),
+        })
+        .collect();
+        let written = String::from_utf8(write_csv(&records).unwrap()).unwrap();
+
+        assert_eq!(
+            written,
+            "\u{feff}subject,gradeType\n\"'=HYPERLINK(\"\"http://evil\"\")\",\n'+1,\n'-1,\n'@SUM(A1),\n'\t=1,\n\"'\r=1\",\nok,\n"
+        );
+    }
+}
@@ -0,0 +1,919 @@
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use actix_web::error::BlockingError;
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::changes::{Change, ChangeEvent};
+use crate::config::StoreConfig;
+use crate::error::KretaError;
+use crate::feeds::FeedCredentials;
+use crate::institute::InstituteCode;
+use crate::requests::get_profile;
+use crate::subscriptions::Subscription;
+
+/// Tokens are mapped to their student for this long, well beyond the life
+/// of Kreta access tokens.
+const TOKEN_RETENTION_HOURS: i64 = 24;
+/// Snapshots kept per student of a resource fetched for date ranges, as the
+/// ranges are chosen by the clients.
+const RANGES_KEPT: i64 = 8;
+
+static SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS students (
+        token_hash TEXT PRIMARY KEY,
+        student TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS students_by_age ON students (created_at);
+    CREATE TABLE IF NOT EXISTS snapshots (
+        student TEXT NOT NULL,
+        snapshot TEXT NOT NULL,
+        data TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        PRIMARY KEY (student, snapshot)
+    );
+    CREATE TABLE IF NOT EXISTS changes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        student TEXT NOT NULL,
+        detected_at INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS changes_of_student ON changes (student, id);
+    CREATE TABLE IF NOT EXISTS feeds (
+        token_hash TEXT PRIMARY KEY,
+        institute TEXT NOT NULL,
+        refresh_token TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS subscriptions (
+        id TEXT PRIMARY KEY,
+        institute TEXT NOT NULL,
+        refresh_token TEXT NOT NULL,
+        webhook_url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        last_event_id INTEGER NOT NULL,
+        created_at TEXT NOT NULL
+    );
+";
+
+/// Data of a student, either fresh from Kreta or the last known version
+/// when Kreta couldn't be reached.
+pub struct Stored<T> {
+    pub data: T,
+    /// When the data was fetched from Kreta, set only for stale data.
+    pub stale_since: Option<DateTime<Utc>>,
+}
+
+impl<T> Stored<T> {
+    fn fresh(data: T) -> Stored<T> {
+        Stored {
+            data,
+            stale_since: None,
+        }
+    }
+
+    /// Flags a response made of stale data with a `Warning` and the time the
+    /// data was fetched in `Last-Modified`.
+    pub fn mark(&self, mut response: HttpResponse) -> HttpResponse {
+        if let Some(stale_since) = self.stale_since {
+            let headers = response.headers_mut();
+            headers.insert(
+                header::WARNING,
+                HeaderValue::from_static("110 kreta-proxy \"Response is Stale\""),
+            );
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            if let Ok(last_modified) =
+                HeaderValue::from_str(&stale_since.format("%a, %d %b %Y %H:%M:%S GMT").to_string())
+            {
+                headers.insert(header::LAST_MODIFIED, last_modified);
+            }
+        }
+        response
+    }
+}
+
+/// Optional SQLite storage of the refined data of every student, so the last
+/// known grades, lessons, tasks, homework and notes can be served while Kreta
+/// is down.
+///
+/// Snapshots belong to the student, not the token, as access tokens expire
+/// quickly. Tokens are mapped to their student by a hash, the first time
+/// they are seen while Kreta is up.
+///
+/// Queries run on the blocking thread pool, so SQLite never stalls the
+/// workers serving requests.
+pub struct StudentStore {
+    connection: Option<Arc<Mutex<Connection>>>,
+    /// How long detected changes are kept.
+    changes_retention: Duration,
+}
+
+impl StudentStore {
+    pub fn open(config: &StoreConfig) -> Result<StudentStore, rusqlite::Error> {
+        let changes_retention = Duration::days(config.changes_retention_days as i64);
+        if !config.enabled {
+            return Ok(StudentStore {
+                connection: None,
+                changes_retention,
+            });
+        }
+        StudentStore::with_connection(Connection::open(&config.path)?, changes_retention)
+    }
+
+    fn with_connection(
+        connection: Connection,
+        changes_retention: Duration,
+    ) -> Result<StudentStore, rusqlite::Error> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(StudentStore {
+            connection: Some(Arc::new(Mutex::new(connection))),
+            changes_retention,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Awaits `fetch`, saving its result as the `snapshot` of the student.
+    /// When Kreta is unavailable the last saved snapshot is served instead.
+    pub async fn fetch<T, F>(
+        &self,
+        token: &str,
+        institute: &InstituteCode,
+        snapshot: &str,
+        fetch: F,
+    ) -> Result<Stored<T>, KretaError>
+    where
+        T: Serialize + DeserializeOwned,
+        F: Future<Output = Result<T, KretaError>>,
+    {
+        if !self.is_enabled() {
+            return fetch.await.map(Stored::fresh);
+        }
+
+        match fetch.await {
+            Ok(data) => {
+                if let Some(student) = self.student(token, institute).await {
+                    self.save(&student, snapshot, &data).await;
+                }
+                Ok(Stored::fresh(data))
+            }
+            Err(err) if err.is_upstream_unavailable() => {
+                let stored = match self.known_student(token).await {
+                    Some(student) => self.load(&student, snapshot).await,
+                    None => None,
+                };
+                match stored {
+                    Some((data, updated_at)) => {
+                        info!(
+                            "Serving {} stored at {} as Kreta failed",
+                            snapshot, updated_at
+                        );
+                        Ok(Stored {
+                            data,
+                            stale_since: Some(updated_at),
+                        })
+                    }
+                    None => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Finds the student `token` belongs to, asking Kreta for the profile of
+    /// the student the first time the token is seen.
+    pub async fn student(&self, token: &str, institute: &InstituteCode) -> Option<String> {
+        if let Some(student) = self.known_student(token).await {
+            return Some(student);
+        }
+
+        let profile = get_profile(token, institute).await.ok()?.refine();
+        Some(self.remember_profile(token, institute, profile.id).await)
+    }
+
+    /// Maps `token` to the student with the `id` of its profile.
+    pub async fn remember_profile(
+        &self,
+        token: &str,
+        institute: &InstituteCode,
+        id: u64,
+    ) -> String {
+        let student = format!("{}:{}", institute, id);
+        self.remember_student(token, &student).await;
+        student
+    }
+
+    pub async fn known_student(&self, token: &str) -> Option<String> {
+        let token_hash = token_hash(token);
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "SELECT student FROM students WHERE token_hash = ?1",
+                    params![token_hash],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(store_failed)
+        })
+        .await
+        .unwrap_or_else(|err| {
+            warn!("Student of a token couldn't be looked up: {}", err);
+            None
+        })
+    }
+
+    /// Maps `token` to `student`, forgetting the tokens seen long ago.
+    async fn remember_student(&self, token: &str, student: &str) {
+        let token_hash = token_hash(token);
+        let student = student.to_string();
+        let result = self
+            .run(move |connection| {
+                let now = Utc::now();
+                let expired = now - Duration::hours(TOKEN_RETENTION_HOURS);
+                connection
+                    .execute(
+                        "INSERT OR REPLACE INTO students (token_hash, student, created_at)
+                         VALUES (?1, ?2, ?3)",
+                        params![token_hash, student, now.to_rfc3339()],
+                    )
+                    .and_then(|_| {
+                        connection.execute(
+                            "DELETE FROM students WHERE created_at < ?1",
+                            params![expired.to_rfc3339()],
+                        )
+                    })
+                    .map_err(store_failed)
+            })
+            .await;
+        if let Err(err) = result {
+            warn!("Student of a token couldn't be saved: {}", err);
+        }
+    }
+
+    /// Saves the `snapshot` of the student. Of snapshots named like
+    /// `lessons:<from>:<to>` only the last few ranges are kept.
+    pub async fn save<T: Serialize>(&self, student: &str, snapshot: &str, data: &T) {
+        if !self.is_enabled() {
+            return;
+        }
+        let data = match serde_json::to_string(data) {
+            Ok(data) => data,
+            Err(err) => {
+                warn!("Snapshot {} couldn't be serialized: {}", snapshot, err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.save_json(student, snapshot, data).await {
+            warn!("Snapshot {} couldn't be saved: {}", snapshot, err);
+        }
+    }
+
+    async fn save_json(
+        &self,
+        student: &str,
+        snapshot: &str,
+        data: String,
+    ) -> Result<(), KretaError> {
+        let student = student.to_string();
+        let name = snapshot.to_string();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO snapshots (student, snapshot, data, updated_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![student, name, data, Utc::now().to_rfc3339()],
+                )
+                .map_err(store_failed)?;
+            if let Some(end) = name.find(':') {
+                let ranges = format!("{}%", &name[..=end]);
+                connection
+                    .execute(
+                        "DELETE FROM snapshots WHERE student = ?1 AND snapshot LIKE ?2
+                         AND snapshot NOT IN (
+                             SELECT snapshot FROM snapshots
+                             WHERE student = ?1 AND snapshot LIKE ?2
+                             ORDER BY updated_at DESC, rowid DESC LIMIT ?3
+                         )",
+                        params![student, ranges, RANGES_KEPT],
+                    )
+                    .map_err(store_failed)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Loads the last saved `snapshot` of the student with the time it was saved.
+    pub async fn load<T: DeserializeOwned>(
+        &self,
+        student: &str,
+        snapshot: &str,
+    ) -> Option<(T, DateTime<Utc>)> {
+        let (data, updated_at) = self
+            .load_json(student, snapshot)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Snapshot {} couldn't be loaded: {}", snapshot, err);
+                None
+            })?;
+
+        let data = serde_json::from_str(&data)
+            .map_err(|err| warn!("Snapshot {} couldn't be parsed: {}", snapshot, err))
+            .ok()?;
+        let updated_at = DateTime::parse_from_rfc3339(&updated_at)
+            .ok()?
+            .with_timezone(&Utc);
+        Some((data, updated_at))
+    }
+
+    async fn load_json(
+        &self,
+        student: &str,
+        snapshot: &str,
+    ) -> Result<Option<(String, String)>, KretaError> {
+        let student = student.to_string();
+        let name = snapshot.to_string();
+        self.run(move |connection| {
+            connection
+                .query_row(
+                    "SELECT data, updated_at FROM snapshots WHERE student = ?1 AND snapshot = ?2",
+                    params![student, name],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )
+                .optional()
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    /// Replaces the `snapshot` of the student with what `update` makes of the
+    /// previous one, recording the changes it found. The connection stays
+    /// locked meanwhile, so concurrent detections can't record a change twice.
+    pub async fn update_snapshot<T, F>(
+        &self,
+        student: &str,
+        snapshot: &str,
+        update: F,
+    ) -> Result<Vec<ChangeEvent>, KretaError>
+    where
+        T: Serialize + DeserializeOwned + 'static,
+        F: FnOnce(Option<T>) -> (T, Vec<Change>) + Send + 'static,
+    {
+        let student = student.to_string();
+        let snapshot = snapshot.to_string();
+        let changes_retention = self.changes_retention;
+        self.run(move |connection| {
+            let previous: Option<String> = connection
+                .query_row(
+                    "SELECT data FROM snapshots WHERE student = ?1 AND snapshot = ?2",
+                    params![student, snapshot],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(store_failed)?;
+            let previous = previous.and_then(|data| {
+                serde_json::from_str(&data)
+                    .map_err(|err| warn!("Snapshot {} couldn't be parsed: {}", snapshot, err))
+                    .ok()
+            });
+
+            let (data, changes) = update(previous);
+            let data = serde_json::to_string(&data)
+                .map_err(|err| KretaError::SerializationError(err.to_string()))?;
+
+            let now = Utc::now();
+            let transaction = connection.transaction().map_err(store_failed)?;
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO snapshots (student, snapshot, data, updated_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![student, snapshot, data, now.to_rfc3339()],
+                )
+                .map_err(store_failed)?;
+            transaction
+                .execute(
+                    "DELETE FROM changes WHERE detected_at < ?1",
+                    params![(now - changes_retention).timestamp_millis()],
+                )
+                .map_err(store_failed)?;
+
+            let mut events = Vec::with_capacity(changes.len());
+            for change in changes {
+                let data = serde_json::to_string(&change)
+                    .map_err(|err| KretaError::SerializationError(err.to_string()))?;
+                transaction
+                    .execute(
+                        "INSERT INTO changes (student, detected_at, data) VALUES (?1, ?2, ?3)",
+                        params![student, now.timestamp_millis(), data],
+                    )
+                    .map_err(store_failed)?;
+                events.push(ChangeEvent {
+                    id: transaction.last_insert_rowid(),
+                    detected_at: now,
+                    change,
+                });
+            }
+            transaction.commit().map_err(store_failed)?;
+            Ok(events)
+        })
+        .await
+    }
+
+    /// Changes of the student detected after `since`, oldest first.
+    pub async fn changes_since(
+        &self,
+        student: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<ChangeEvent>, KretaError> {
+        self.changes_where(student, "detected_at > ?2", since.timestamp_millis())
+            .await
+    }
+
+    /// Changes of the student recorded after the change with `id`, oldest first.
+    pub async fn changes_after(
+        &self,
+        student: &str,
+        id: i64,
+    ) -> Result<Vec<ChangeEvent>, KretaError> {
+        self.changes_where(student, "id > ?2", id).await
+    }
+
+    /// The id of the last change recorded for any student.
+    pub async fn last_change_id(&self) -> Result<i64, KretaError> {
+        self.run(|connection| {
+            connection
+                .query_row(
+                    "SELECT COALESCE(MAX(id), 0) FROM changes",
+                    params![],
+                    |row| row.get(0),
+                )
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    async fn changes_where(
+        &self,
+        student: &str,
+        condition: &'static str,
+        value: i64,
+    ) -> Result<Vec<ChangeEvent>, KretaError> {
+        let student = student.to_string();
+        let rows = self
+            .run(move |connection| {
+                let mut statement = connection
+                    .prepare(&format!(
+                        "SELECT id, detected_at, data FROM changes
+                         WHERE student = ?1 AND {} ORDER BY id",
+                        condition
+                    ))
+                    .map_err(store_failed)?;
+                let rows = statement
+                    .query_map(params![student, value], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)?,
+                            row.get::<_, i64>(1)?,
+                            row.get::<_, String>(2)?,
+                        ))
+                    })
+                    .map_err(store_failed)?;
+                rows.collect::<Result<Vec<_>, _>>().map_err(store_failed)
+            })
+            .await?;
+
+        let mut events = Vec::with_capacity(rows.len());
+        for (id, detected_at, data) in rows {
+            match serde_json::from_str(&data) {
+                Ok(change) => events.push(ChangeEvent {
+                    id,
+                    detected_at: Utc.timestamp_millis(detected_at),
+                    change,
+                }),
+                Err(err) => warn!("Change {} couldn't be parsed: {}", id, err),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Saves a calendar feed, keyed by the hash of its token like students.
+    pub async fn add_feed(
+        &self,
+        feed_token: &str,
+        credentials: &FeedCredentials,
+    ) -> Result<(), KretaError> {
+        let token_hash = token_hash(feed_token);
+        let credentials = credentials.clone();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO feeds (token_hash, institute, refresh_token, created_at)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        token_hash,
+                        credentials.url.as_str(),
+                        credentials.refresh_token,
+                        Utc::now().to_rfc3339()
+                    ],
+                )
+                .map(|_| ())
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    pub async fn feed(&self, feed_token: &str) -> Result<Option<FeedCredentials>, KretaError> {
+        let token_hash = token_hash(feed_token);
+        let row: Option<(String, String)> = self
+            .run(move |connection| {
+                connection
+                    .query_row(
+                        "SELECT institute, refresh_token FROM feeds WHERE token_hash = ?1",
+                        params![token_hash],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )
+                    .optional()
+                    .map_err(store_failed)
+            })
+            .await?;
+
+        match row {
+            Some((institute, refresh_token)) => Ok(Some(FeedCredentials {
+                url: InstituteCode::parse(&institute)?,
+                refresh_token,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_feed_token(
+        &self,
+        feed_token: &str,
+        refresh_token: &str,
+    ) -> Result<(), KretaError> {
+        let (token_hash, refresh_token) = (token_hash(feed_token), refresh_token.to_string());
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "UPDATE feeds SET refresh_token = ?2 WHERE token_hash = ?1",
+                    params![token_hash, refresh_token],
+                )
+                .map(|_| ())
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    pub async fn remove_feed(&self, feed_token: &str) -> Result<bool, KretaError> {
+        let token_hash = token_hash(feed_token);
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "DELETE FROM feeds WHERE token_hash = ?1",
+                    params![token_hash],
+                )
+                .map(|removed| removed > 0)
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    pub async fn add_subscription(&self, subscription: &Subscription) -> Result<(), KretaError> {
+        let subscription = subscription.clone();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "INSERT INTO subscriptions
+                     (id, institute, refresh_token, webhook_url, secret, last_event_id, created_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        subscription.id,
+                        subscription.institute.as_str(),
+                        subscription.refresh_token,
+                        subscription.webhook_url,
+                        subscription.secret,
+                        subscription.last_event_id,
+                        Utc::now().to_rfc3339()
+                    ],
+                )
+                .map(|_| ())
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    pub async fn subscriptions(&self) -> Result<Vec<Subscription>, KretaError> {
+        let rows = self
+            .run(|connection| {
+                let mut statement = connection
+                    .prepare(
+                        "SELECT id, institute, refresh_token, webhook_url, secret, last_event_id
+                         FROM subscriptions ORDER BY created_at",
+                    )
+                    .map_err(store_failed)?;
+                let rows = statement
+                    .query_map(params![], |row| {
+                        Ok((
+                            row.get::<_, String>(0)?,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, String>(2)?,
+                            row.get::<_, String>(3)?,
+                            row.get::<_, String>(4)?,
+                            row.get::<_, i64>(5)?,
+                        ))
+                    })
+                    .map_err(store_failed)?;
+                rows.collect::<Result<Vec<_>, _>>().map_err(store_failed)
+            })
+            .await?;
+
+        let mut subscriptions = Vec::with_capacity(rows.len());
+        for (id, institute, refresh_token, webhook_url, secret, last_event_id) in rows {
+            match InstituteCode::parse(&institute) {
+                Ok(institute) => subscriptions.push(Subscription {
+                    id,
+                    institute,
+                    refresh_token,
+                    webhook_url,
+                    secret,
+                    last_event_id,
+                }),
+                Err(err) => warn!("Subscription has an invalid institute: {}", err),
+            }
+        }
+        Ok(subscriptions)
+    }
+
+    pub async fn update_subscription_token(
+        &self,
+        id: &str,
+        refresh_token: &str,
+    ) -> Result<(), KretaError> {
+        let (id, refresh_token) = (id.to_string(), refresh_token.to_string());
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "UPDATE subscriptions SET refresh_token = ?2 WHERE id = ?1",
+                    params![id, refresh_token],
+                )
+                .map(|_| ())
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    /// Marks the changes up to `last_event_id` delivered to the subscription.
+    pub async fn advance_subscription(
+        &self,
+        id: &str,
+        last_event_id: i64,
+    ) -> Result<(), KretaError> {
+        let id = id.to_string();
+        self.run(move |connection| {
+            connection
+                .execute(
+                    "UPDATE subscriptions SET last_event_id = ?2 WHERE id = ?1",
+                    params![id, last_event_id],
+                )
+                .map(|_| ())
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    pub async fn remove_subscription(&self, id: &str) -> Result<bool, KretaError> {
+        let id = id.to_string();
+        self.run(move |connection| {
+            connection
+                .execute("DELETE FROM subscriptions WHERE id = ?1", params![id])
+                .map(|removed| removed > 0)
+                .map_err(store_failed)
+        })
+        .await
+    }
+
+    /// Runs `query` with the connection on the blocking thread pool.
+    async fn run<R, F>(&self, query: F) -> Result<R, KretaError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<R, KretaError> + Send + 'static,
+    {
+        let connection = self.connection.clone().ok_or(KretaError::StoreDisabled)?;
+        web::block(move || query(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|err| match err {
+                BlockingError::Error(err) => err,
+                BlockingError::Canceled => {
+                    KretaError::StoreFailed(String::from("the query was canceled"))
+                }
+            })
+    }
+}
+
+fn store_failed(err: rusqlite::Error) -> KretaError {
+    KretaError::StoreFailed(err.to_string())
+}
+
+/// Tokens are never kept as they are, only this hash of them, both here and
+/// in the memory of the cache and the rate limiter. Unlike the hashers of the
+/// standard library SHA-256 is collision resistant, so two tokens never share
+/// data, and it stays the same across restarts and Rust releases.
+pub fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod store_test {
+    use super::*;
+    use crate::changes::{ChangeKind, Watched};
+    use serde_json::json;
+
+    fn store() -> StudentStore {
+        StudentStore::with_connection(Connection::open_in_memory().unwrap(), Duration::days(30))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_snapshots_are_saved_per_student() {
+        let store = store();
+
+        assert_eq!(store.known_student("token").await, None);
+        store.remember_student("token", "klik0001:42").await;
+        assert_eq!(
+            store.known_student("token").await,
+            Some(String::from("klik0001:42"))
+        );
+
+        store
+            .save("klik0001:42", "notes", &vec![String::from("first")])
+            .await;
+        store
+            .save("klik0001:42", "notes", &vec![String::from("second")])
+            .await;
+        let (notes, _updated_at): (Vec<String>, _) =
+            store.load("klik0001:42", "notes").await.unwrap();
+        assert_eq!(notes, vec![String::from("second")]);
+
+        assert!(store
+            .load::<Vec<String>>("klik0001:43", "notes")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ranges_are_bounded() {
+        let store = store();
+        for day in 1..=RANGES_KEPT + 2 {
+            let snapshot = format!("lessons:2020-02-{:02}:2020-02-28", day);
+            store.save("klik0001:42", &snapshot, &day).await;
+        }
+        store.save("klik0001:42", "homework", &0).await;
+
+        assert!(store
+            .load::<i64>("klik0001:42", "lessons:2020-02-01:2020-02-28")
+            .await
+            .is_none());
+        let last = format!("lessons:2020-02-{:02}:2020-02-28", RANGES_KEPT + 2);
+        assert!(store.load::<i64>("klik0001:42", &last).await.is_some());
+        assert!(store.load::<i64>("klik0001:42", "homework").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_recorded() {
+        let store = store();
+        let since = Utc::now() - Duration::seconds(1);
+        let change = Change {
+            resource: Watched::Notes,
+            kind: ChangeKind::Added,
+            item: json!({"id": 1}),
+            previous: None,
+        };
+
+        let added = change.clone();
+        let events = store
+            .update_snapshot(
+                "klik0001:42",
+                "changes:notes",
+                |previous: Option<Vec<i64>>| {
+                    assert_eq!(previous, None);
+                    (vec![1], vec![added])
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        let events = store
+            .update_snapshot(
+                "klik0001:42",
+                "changes:notes",
+                |previous: Option<Vec<i64>>| {
+                    assert_eq!(previous, Some(vec![1]));
+                    (vec![1], vec![])
+                },
+            )
+            .await
+            .unwrap();
+        assert!(events.is_empty());
+
+        let recorded = store.changes_since("klik0001:42", since).await.unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].change, change);
+        assert!(store
+            .changes_since("klik0001:42", recorded[0].detected_at)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(store
+            .changes_since("klik0001:43", since)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_are_saved() {
+        let store = store();
+        let subscription = Subscription::new(
+            InstituteCode::parse("klik0001").unwrap(),
+            String::from("refresh"),
+            String::from("https://example.com/hooks/kreta"),
+            store.last_change_id().await.unwrap(),
+        );
+        store.add_subscription(&subscription).await.unwrap();
+        store
+            .update_subscription_token(&subscription.id, "rotated")
+            .await
+            .unwrap();
+        store
+            .advance_subscription(&subscription.id, 7)
+            .await
+            .unwrap();
+
+        let subscriptions = store.subscriptions().await.unwrap();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].refresh_token, "rotated");
+        assert_eq!(subscriptions[0].last_event_id, 7);
+
+        assert!(store.remove_subscription(&subscription.id).await.unwrap());
+        assert!(!store.remove_subscription(&subscription.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_feeds_are_saved() {
+        let store = store();
+        let credentials = FeedCredentials {
+            url: InstituteCode::parse("klik0001").unwrap(),
+            refresh_token: String::from("refresh"),
+        };
+        store.add_feed("feed", &credentials).await.unwrap();
+        store.update_feed_token("feed", "rotated").await.unwrap();
+
+        let feed = store.feed("feed").await.unwrap().unwrap();
+        assert_eq!(feed.url.as_str(), "klik0001");
+        assert_eq!(feed.refresh_token, "rotated");
+        assert!(store.feed("other").await.unwrap().is_none());
+
+        assert!(store.remove_feed("feed").await.unwrap());
+        assert!(store.feed("feed").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_token_hash_is_stable() {
+        assert_eq!(
+            token_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_stale_response_is_flagged() {
+        let stored = Stored {
+            data: (),
+            stale_since: Some(
+                DateTime::parse_from_rfc3339("2020-02-03T18:30:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc),
+            ),
+        };
+        let response = stored.mark(HttpResponse::Ok().finish());
+
+        assert!(response.headers().get(header::WARNING).is_some());
+        assert_eq!(
+            response.headers().get(header::LAST_MODIFIED).unwrap(),
+            "Mon, 03 Feb 2020 18:30:00 GMT"
+        );
+    }
+}
@@ -0,0 +1,563 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::changes::{detect, Change, ChangeEvent, ChangeKind, Watched};
+use crate::config;
+use crate::discord;
+use crate::error::KretaError;
+use crate::institute::InstituteCode;
+use crate::metrics;
+use crate::requests::refresh_access_token;
+use crate::store::StudentStore;
+
+const SUBSCRIPTION_ID_LENGTH: usize = 40;
+const SECRET_LENGTH: usize = 40;
+
+/// Header with the signature of webhook bodies.
+pub static SIGNATURE_HEADER: &str = "x-kreta-proxy-signature";
+/// Header with the unix time a webhook body was signed at, which is signed
+/// too so old deliveries can't be replayed.
+pub static TIMESTAMP_HEADER: &str = "x-kreta-proxy-timestamp";
+
+/// A webhook notified about the changes of a student, polled with the
+/// refresh token of the student like calendar feeds.
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub id: String,
+    pub institute: InstituteCode,
+    pub refresh_token: String,
+    pub webhook_url: String,
+    /// Key of the signature of webhook bodies, only known by the subscriber.
+    pub secret: String,
+    /// The last change delivered to the webhook.
+    pub last_event_id: i64,
+}
+
+impl Subscription {
+    pub fn new(
+        institute: InstituteCode,
+        refresh_token: String,
+        webhook_url: String,
+        last_event_id: i64,
+    ) -> Subscription {
+        Subscription {
+            id: random_string(SUBSCRIPTION_ID_LENGTH),
+            institute,
+            refresh_token,
+            webhook_url,
+            secret: random_string(SECRET_LENGTH),
+            last_event_id,
+        }
+    }
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .collect()
+}
+
+/// A webhook url which only resolves to public addresses, with the address
+/// its deliveries connect to.
+#[derive(Debug)]
+pub struct WebhookTarget {
+    pub url: reqwest::Url,
+    address: SocketAddr,
+}
+
+impl WebhookTarget {
+    /// A client connecting to the checked address instead of resolving the
+    /// host again, which could have been pointed to an internal address in
+    /// the meantime. Redirects aren't followed for the same reason.
+    fn client(&self, timeout: Duration) -> Result<reqwest::Client, String> {
+        let builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none());
+        let builder = match self.url.domain() {
+            Some(domain) => builder.resolve(domain, self.address),
+            None => builder,
+        };
+        builder.build().map_err(|err| err.to_string())
+    }
+}
+
+/// Checks that webhooks are https urls of public hosts, so subscriptions
+/// can't be used to make the proxy post to its own network.
+pub async fn parse_webhook_url(webhook_url: &str) -> Result<String, KretaError> {
+    Ok(resolve_webhook(webhook_url).await?.url.into_string())
+}
+
+/// Resolves the host of a webhook, failing unless every address it resolves
+/// to is public. Hosts are resolved again before every delivery, as names
+/// can be pointed somewhere else after subscribing.
+pub async fn resolve_webhook(webhook_url: &str) -> Result<WebhookTarget, KretaError> {
+    let invalid = || KretaError::InvalidWebhookUrl(webhook_url.to_string());
+    let url = match reqwest::Url::parse(webhook_url) {
+        Ok(url) if url.scheme() == "https" => url,
+        _ => return Err(invalid()),
+    };
+    let port = url.port_or_known_default().ok_or_else(invalid)?;
+
+    let addresses: Vec<SocketAddr> = match url.domain() {
+        Some(domain) => tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|_err| invalid())?
+            .collect(),
+        None => {
+            let host = url.host_str().ok_or_else(invalid)?;
+            let ip: IpAddr = host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .map_err(|_err| invalid())?;
+            vec![SocketAddr::new(ip, port)]
+        }
+    };
+    match addresses.first() {
+        Some(&address) if addresses.iter().all(|address| is_public(address.ip())) => {
+            Ok(WebhookTarget { url, address })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    // "This network", carrier-grade NAT, IETF protocol assignments,
+    // benchmarking and the reserved 240.0.0.0/4
+    let reserved = first == 0
+        || (first == 100 && (second & 0b1100_0000) == 64)
+        || (first == 192 && second == 0 && third == 0)
+        || (first == 198 && (second & 0b1111_1110) == 18)
+        || first >= 240;
+    !(reserved
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation())
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4-mapped and IPv4-compatible addresses (::ffff:127.0.0.1, ::1) reach
+    // IPv4 hosts
+    if let Some(ip) = ip.to_ipv4() {
+        return is_public_v4(ip);
+    }
+    let segments = ip.segments();
+    let unique_local = (segments[0] & 0xfe00) == 0xfc00;
+    let link_local = (segments[0] & 0xffc0) == 0xfe80;
+    let documentation = segments[0] == 0x2001 && segments[1] == 0x0db8;
+    // NAT64 (64:ff9b::/96 and the local-use 64:ff9b:1::/48) and 6to4 reach
+    // IPv4 hosts too
+    let nat64 = segments[0] == 0x64 && segments[1] == 0xff9b;
+    let six_to_four = segments[0] == 0x2002;
+    !(unique_local || link_local || documentation || nat64 || six_to_four || ip.is_multicast())
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionResponse {
+    pub subscription_id: String,
+    pub secret: String,
+}
+
+/// Changes worth a notification.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    #[serde(rename = "grade.added")]
+    NewGrade,
+    #[serde(rename = "note.added")]
+    NewNote,
+    #[serde(rename = "lesson.cancelled")]
+    CancelledLesson,
+    #[serde(rename = "lesson.substituted")]
+    Substitution,
+    #[serde(rename = "task.added")]
+    NewTask,
+}
+
+impl EventKind {
+    /// The event `change` is notified as, if any.
+    pub fn of(change: &Change) -> Option<EventKind> {
+        match (change.resource, change.kind) {
+            (Watched::Grades, ChangeKind::Added) => Some(EventKind::NewGrade),
+            (Watched::Notes, ChangeKind::Added) => Some(EventKind::NewNote),
+            (Watched::Tasks, ChangeKind::Added) => Some(EventKind::NewTask),
+            (Watched::Lessons, ChangeKind::Modified)
+                if is_cancelled(&change.item)
+                    && !change.previous.as_ref().map_or(false, is_cancelled) =>
+            {
+                Some(EventKind::CancelledLesson)
+            }
+            (Watched::Lessons, ChangeKind::Added) | (Watched::Lessons, ChangeKind::Modified)
+                if is_stand_in(&change.item)
+                    && !change.previous.as_ref().map_or(false, is_stand_in) =>
+            {
+                Some(EventKind::Substitution)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn is_cancelled(lesson: &Value) -> bool {
+    lesson.get("cancelled").and_then(Value::as_bool) == Some(true)
+}
+
+fn is_stand_in(lesson: &Value) -> bool {
+    lesson.get("standIn").and_then(Value::as_bool) == Some(true)
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub detected_at: DateTime<Utc>,
+    pub item: Value,
+}
+
+impl Event {
+    pub fn of(change: &ChangeEvent) -> Option<Event> {
+        EventKind::of(&change.change).map(|kind| Event {
+            id: change.id,
+            kind,
+            detected_at: change.detected_at,
+            item: change.change.item.clone(),
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct WebhookBody<'a> {
+    subscription_id: &'a str,
+    sent_at: DateTime<Utc>,
+    events: &'a [Event],
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of `<timestamp>.<body>` keyed
+/// with `secret`.
+pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let signed = [format!("{}.", timestamp).as_bytes(), body].concat();
+    format!("sha256={}", hmac_sha256(secret, &signed))
+}
+
+fn hmac_sha256(secret: &str, message: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.input(message);
+    mac.result()
+        .code()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks every subscription for changes every `subscriptions.poll_interval_secs`.
+pub async fn poll(store: web::Data<StudentStore>) {
+    let subscriptions_config = &config::get().subscriptions;
+    let mut interval = tokio::time::interval(subscriptions_config.poll_interval());
+
+    loop {
+        interval.tick().await;
+
+        let subscriptions = match store.subscriptions().await {
+            Ok(subscriptions) => subscriptions,
+            Err(err) => {
+                error!("Subscriptions couldn't be loaded: {}", err);
+                continue;
+            }
+        };
+        futures::stream::iter(subscriptions)
+            .for_each_concurrent(subscriptions_config.max_concurrent_polls, |subscription| {
+                check_subscription(&store, subscription)
+            })
+            .await;
+    }
+}
+
+async fn check_subscription(store: &StudentStore, subscription: Subscription) {
+    match poll_subscription(store, &subscription).await {
+        Ok(()) => {}
+        Err(KretaError::InvalidGrant(_)) => {
+            info!(
+                "Removing subscription {} as its refresh token was rejected",
+                short_id(&subscription)
+            );
+            if let Err(err) = store.remove_subscription(&subscription.id).await {
+                error!("Subscription couldn't be removed: {}", err);
+            }
+        }
+        Err(err) => warn!(
+            "Subscription {} couldn't be checked: {}",
+            short_id(&subscription),
+            err
+        ),
+    }
+}
+
+/// Detects the changes of the student of `subscription`, posting the ones
+/// not delivered yet to its webhook. Failed deliveries are tried again at
+/// the next poll.
+async fn poll_subscription(
+    store: &StudentStore,
+    subscription: &Subscription,
+) -> Result<(), KretaError> {
+    let authentication =
+        refresh_access_token(&subscription.institute, &subscription.refresh_token).await?;
+    store
+        .update_subscription_token(&subscription.id, &authentication.refresh_token)
+        .await?;
+
+    let (student, _) = detect(store, &authentication.access_token, &subscription.institute).await?;
+    let changes = store
+        .changes_after(&student, subscription.last_event_id)
+        .await?;
+    let last_event_id = match changes.last() {
+        Some(change) => change.id,
+        None => return Ok(()),
+    };
+
+    let events: Vec<Event> = changes.iter().filter_map(Event::of).collect();
+    let is_discord = discord::is_discord_webhook(&subscription.webhook_url);
+    let batches = if events.is_empty() {
+        Vec::new()
+    } else if is_discord {
+        discord::batches(&events)
+    } else {
+        vec![&events[..]]
+    };
+    if batches.is_empty() {
+        return store
+            .advance_subscription(&subscription.id, last_event_id)
+            .await;
+    }
+
+    let timeout = config::get().subscriptions.webhook_timeout();
+    let client = match resolve_webhook(&subscription.webhook_url).await {
+        Ok(target) => target.client(timeout),
+        Err(err) => Err(err.to_string()),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            metrics::count_webhook_delivery(false);
+            warn!(
+                "Webhook of subscription {} was skipped: {}",
+                short_id(subscription),
+                err
+            );
+            return Ok(());
+        }
+    };
+
+    // Batches delivered before a failure aren't sent again
+    for batch in batches {
+        let delivered = if is_discord {
+            discord::deliver(&client, &subscription.webhook_url, batch).await
+        } else {
+            deliver(&client, subscription, batch).await
+        };
+        metrics::count_webhook_delivery(delivered.is_ok());
+        if let Err(err) = delivered {
+            warn!(
+                "Webhook of subscription {} failed: {}",
+                short_id(subscription),
+                err
+            );
+            return Ok(());
+        }
+        if let Some(event) = batch.last() {
+            store
+                .advance_subscription(&subscription.id, event.id)
+                .await?;
+        }
+    }
+    store
+        .advance_subscription(&subscription.id, last_event_id)
+        .await
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    subscription: &Subscription,
+    events: &[Event],
+) -> Result<(), String> {
+    let sent_at = Utc::now();
+    let body = serde_json::to_vec(&WebhookBody {
+        subscription_id: &subscription.id,
+        sent_at,
+        events,
+    })
+    .map_err(|err| err.to_string())?;
+
+    let timestamp = sent_at.timestamp();
+    let response = client
+        .post(&subscription.webhook_url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(
+            SIGNATURE_HEADER,
+            signature(&subscription.secret, timestamp, &body),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("webhook answered {}", response.status()))
+    }
+}
+
+/// The start of the id of a subscription, enough to tell them apart in logs
+/// without leaking the id needed to remove it.
+fn short_id(subscription: &Subscription) -> &str {
+    &subscription.id[..8]
+}
+
+#[cfg(test)]
+mod subscriptions_test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_signature() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            hmac_sha256("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            signature("Jefe", 1_580_800_000, b"{}"),
+            format!("sha256={}", hmac_sha256("Jefe", b"1580800000.{}"))
+        );
+    }
+
+    #[test]
+    fn test_event_kinds() {
+        let change = |resource, kind, item, previous| Change {
+            resource,
+            kind,
+            item,
+            previous,
+        };
+
+        assert_eq!(
+            EventKind::of(&change(Watched::Grades, ChangeKind::Added, json!({}), None)),
+            Some(EventKind::NewGrade)
+        );
+        assert_eq!(
+            EventKind::of(&change(
+                Watched::Grades,
+                ChangeKind::Removed,
+                json!({}),
+                None
+            )),
+            None
+        );
+        assert_eq!(
+            EventKind::of(&change(
+                Watched::Lessons,
+                ChangeKind::Modified,
+                json!({"cancelled": true}),
+                Some(json!({"cancelled": false}))
+            )),
+            Some(EventKind::CancelledLesson)
+        );
+        assert_eq!(
+            EventKind::of(&change(
+                Watched::Lessons,
+                ChangeKind::Modified,
+                json!({"cancelled": true, "room": "12"}),
+                Some(json!({"cancelled": true, "room": "11"}))
+            )),
+            None
+        );
+        assert_eq!(
+            EventKind::of(&change(
+                Watched::Lessons,
+                ChangeKind::Added,
+                json!({"cancelled": false, "standIn": true}),
+                None
+            )),
+            Some(EventKind::Substitution)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_webhook_urls() {
+        assert!(parse_webhook_url("https://93.184.216.34/hooks/kreta")
+            .await
+            .is_ok());
+        assert!(parse_webhook_url("https://[2606:2800:220:1::]/hooks")
+            .await
+            .is_ok());
+
+        let target = resolve_webhook("https://93.184.216.34:8443/hooks")
+            .await
+            .unwrap();
+        assert_eq!(target.address, "93.184.216.34:8443".parse().unwrap());
+
+        for rejected in &[
+            "http://93.184.216.34/hooks/kreta",
+            "ftp://example.com/",
+            "not a url",
+            "https://127.0.0.1/",
+            "https://localhost:8080/",
+            "https://10.1.2.3/",
+            "https://172.16.0.1/",
+            "https://192.168.1.1/",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://0.0.0.0/",
+            "https://100.64.0.1/",
+            "https://192.0.0.8/",
+            "https://198.18.0.1/",
+            "https://198.19.255.1/",
+            "https://240.0.0.1/",
+            "https://255.255.255.255/",
+            "https://[::1]/",
+            "https://[fd00::1]/",
+            "https://[fe80::1]/",
+            "https://[::ffff:127.0.0.1]/",
+            "https://[::ffff:10.0.0.1]/",
+            "https://[::127.0.0.1]/",
+            "https://[::]/",
+            "https://[64:ff9b::7f00:1]/",
+            "https://[64:ff9b:1::a00:1]/",
+            "https://[2002:7f00:1::1]/",
+            "https://[2001:db8::1]/",
+        ] {
+            assert!(
+                parse_webhook_url(rejected).await.is_err(),
+                "{} was accepted",
+                rejected
+            );
+        }
+    }
+}
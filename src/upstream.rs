@@ -0,0 +1,441 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{self, UpstreamConfig};
+use crate::error::KretaError;
+use crate::metrics;
+
+static LIMITER: Lazy<Limiter> = Lazy::new(|| Limiter::new(&config::get().upstream));
+static CIRCUITS: Lazy<CircuitBreaker> = Lazy::new(|| CircuitBreaker::new(&config::get().upstream));
+
+/// Limits how many calls the proxy makes to Kreta at once, both in total and
+/// to a single institute, so a slow school can't tie up every worker and a
+/// busy period doesn't overload the fragile school servers.
+pub struct Limiter {
+    global: Arc<Semaphore>,
+    per_institute: Mutex<HashMap<String, Arc<Semaphore>>>,
+    max_per_institute: usize,
+    /// Number of calls waiting for or acquiring their permits.
+    queued: AtomicUsize,
+    max_queued: usize,
+    queue_timeout: Duration,
+}
+
+/// Permission to call Kreta, the slots are released when it is dropped.
+pub struct Permit {
+    _global: OwnedSemaphorePermit,
+    _institute: OwnedSemaphorePermit,
+}
+
+impl Limiter {
+    pub fn new(config: &UpstreamConfig) -> Limiter {
+        Limiter {
+            global: Arc::new(Semaphore::new(config.max_concurrent)),
+            per_institute: Mutex::new(HashMap::new()),
+            max_per_institute: config.max_concurrent_per_institute,
+            queued: AtomicUsize::new(0),
+            max_queued: config.max_queued,
+            queue_timeout: config.queue_timeout(),
+        }
+    }
+
+    /// Waits for a free slot to call `institute`, failing right away when too
+    /// many calls are already waiting, or after the queue timeout.
+    pub async fn acquire(&self, institute: &str) -> Result<Permit, KretaError> {
+        let institute_semaphore = self.institute_semaphore(institute);
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(KretaError::UpstreamQueueFull);
+        }
+
+        let acquired = tokio::time::timeout(self.queue_timeout, async {
+            let institute = institute_semaphore.acquire_owned().await;
+            let global = self.global.clone().acquire_owned().await;
+            Permit {
+                _global: global,
+                _institute: institute,
+            }
+        })
+        .await;
+
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        acquired.map_err(|_elapsed| KretaError::UpstreamQueueTimeout)
+    }
+
+    fn institute_semaphore(&self, institute: &str) -> Arc<Semaphore> {
+        let mut per_institute = self.per_institute.lock().unwrap();
+        if !per_institute.contains_key(institute) {
+            // Permits and waiting calls hold a clone of the semaphore, so
+            // institutes nobody is calling are only referenced by the map
+            per_institute.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        }
+        per_institute
+            .entry(institute.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_institute)))
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Circuit {
+    Closed {
+        failures: u32,
+        last_failure: Instant,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A single probe call is let through to see whether Kreta recovered.
+    HalfOpen {
+        probe_started: Instant,
+    },
+}
+
+/// Stops calling the Kreta of an institute after repeated failures, so
+/// requests to a school which is down fail fast instead of waiting for a
+/// full timeout. After a while a probe call is let through, closing the
+/// circuit again when it succeeds.
+pub struct CircuitBreaker {
+    circuits: Mutex<HashMap<String, Circuit>>,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &UpstreamConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            circuits: Mutex::new(HashMap::new()),
+            failure_threshold: config.circuit_failure_threshold,
+            open_duration: config.circuit_open_duration(),
+        }
+    }
+
+    /// Whether `circuit` saw no calls for long enough to be forgotten, like
+    /// an institute which only failed a few times long ago.
+    fn is_idle(&self, circuit: &Circuit, now: Instant) -> bool {
+        let idle_since = match *circuit {
+            Circuit::Closed { last_failure, .. } => last_failure,
+            Circuit::Open { until } => until,
+            Circuit::HalfOpen { probe_started } => probe_started + self.open_duration,
+        };
+        now >= idle_since + self.open_duration
+    }
+
+    /// Checks whether `institute` may be called, returning how long to wait
+    /// when its circuit is open.
+    fn check(&self, institute: &str, now: Instant) -> Result<(), Duration> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = match circuits.get_mut(institute) {
+            Some(circuit) => circuit,
+            None => return Ok(()),
+        };
+
+        match *circuit {
+            Circuit::Closed { .. } => Ok(()),
+            Circuit::Open { until } if now < until => Err(until - now),
+            Circuit::Open { .. } => {
+                *circuit = Circuit::HalfOpen { probe_started: now };
+                Ok(())
+            }
+            // A probe which never reported back is replaced by a new one
+            Circuit::HalfOpen { probe_started } => {
+                let probing = now.duration_since(probe_started);
+                if probing < self.open_duration {
+                    Err(self.open_duration - probing)
+                } else {
+                    *circuit = Circuit::HalfOpen { probe_started: now };
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    fn record(&self, institute: &str, success: bool, now: Instant) {
+        if self.failure_threshold == 0 {
+            return;
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        if success {
+            if let Some(Circuit::HalfOpen { .. }) = circuits.remove(institute) {
+                info!("Circuit of {} closed, Kreta answers again", institute);
+            }
+            return;
+        }
+
+        if !circuits.contains_key(institute) {
+            circuits.retain(|_, circuit| !self.is_idle(circuit, now));
+        }
+        let circuit = circuits
+            .entry(institute.to_string())
+            .or_insert(Circuit::Closed {
+                failures: 0,
+                last_failure: now,
+            });
+        let opened = match *circuit {
+            Circuit::Closed { failures, .. } if failures + 1 < self.failure_threshold => {
+                *circuit = Circuit::Closed {
+                    failures: failures + 1,
+                    last_failure: now,
+                };
+                false
+            }
+            Circuit::Open { .. } => false,
+            Circuit::Closed { .. } | Circuit::HalfOpen { .. } => {
+                *circuit = Circuit::Open {
+                    until: now + self.open_duration,
+                };
+                true
+            }
+        };
+
+        if opened {
+            metrics::count_circuit_opened(institute);
+            warn!(
+                "Circuit of {} opened for {}s after repeated failures",
+                institute,
+                self.open_duration.as_secs()
+            );
+        }
+    }
+}
+
+/// Fails fast with `KretaError::CircuitOpen` while the circuit of
+/// `institute` is open.
+pub fn check_circuit(institute: &str) -> Result<(), KretaError> {
+    CIRCUITS
+        .check(institute, Instant::now())
+        .map_err(|retry_after| KretaError::CircuitOpen(retry_after.as_secs() + 1))
+}
+
+/// Records whether a call to `institute` succeeded, server errors and
+/// transport errors count as failures.
+pub fn record_result(institute: &str, success: bool) {
+    CIRCUITS.record(institute, success, Instant::now());
+}
+
+/// Acquires a slot from the limiter configured for the server.
+pub async fn acquire(institute: &str) -> Result<Permit, KretaError> {
+    LIMITER.acquire(institute).await
+}
+
+/// Whether Kreta answered with a temporary failure worth retrying.
+pub fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::SERVICE_UNAVAILABLE
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+/// Reads the `Retry-After` header, given either in seconds or as a date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        date.signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or(Duration::from_secs(0)),
+    )
+}
+
+/// How long to wait before retrying after `attempt` failed. Kreta's
+/// `Retry-After` is honoured, but when it asks for more than the maximum
+/// delay the call isn't retried at all.
+pub fn retry_delay(
+    config: &UpstreamConfig,
+    attempt: u32,
+    retry_after: Option<Duration>,
+) -> Option<Duration> {
+    let max_delay = config.retry_max_delay();
+    match retry_after {
+        Some(retry_after) if retry_after > max_delay => None,
+        Some(retry_after) => Some(retry_after),
+        None => Some(backoff(
+            config,
+            attempt,
+            rand::thread_rng().gen_range(0.5, 1.0),
+        )),
+    }
+}
+
+/// Exponential backoff scaled by `jitter`, so clients failing together
+/// don't retry together.
+fn backoff(config: &UpstreamConfig, attempt: u32, jitter: f64) -> Duration {
+    let exponential = config
+        .retry_base_delay()
+        .checked_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .unwrap_or_else(|| config.retry_max_delay());
+    exponential.min(config.retry_max_delay()).mul_f64(jitter)
+}
+
+#[cfg(test)]
+mod upstream_test {
+    use super::*;
+
+    fn limiter(max_per_institute: usize, max_queued: usize) -> Limiter {
+        Limiter::new(&UpstreamConfig {
+            max_concurrent: 2,
+            max_concurrent_per_institute: max_per_institute,
+            max_queued,
+            queue_timeout_millis: 50,
+            ..UpstreamConfig::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_per_institute_limit_times_out() {
+        let limiter = limiter(1, 10);
+
+        let _first = limiter.acquire("klik0001").await.unwrap();
+        assert!(limiter.acquire("klik0002").await.is_ok());
+        match limiter.acquire("klik0001").await {
+            Err(KretaError::UpstreamQueueTimeout) => {}
+            other => panic!("Expected a queue timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_global_limit_and_release() {
+        let limiter = limiter(2, 10);
+
+        let first = limiter.acquire("klik0001").await.unwrap();
+        let _second = limiter.acquire("klik0002").await.unwrap();
+        assert!(limiter.acquire("klik0003").await.is_err());
+
+        drop(first);
+        assert!(limiter.acquire("klik0003").await.is_ok());
+    }
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let config = UpstreamConfig {
+            retry_base_delay_millis: 100,
+            retry_max_delay_millis: 1000,
+            ..UpstreamConfig::default()
+        };
+
+        assert_eq!(backoff(&config, 1, 1.0), Duration::from_millis(100));
+        assert_eq!(backoff(&config, 3, 1.0), Duration::from_millis(400));
+        assert_eq!(backoff(&config, 3, 0.5), Duration::from_millis(200));
+        assert_eq!(backoff(&config, 10, 1.0), Duration::from_millis(1000));
+        assert_eq!(backoff(&config, 40, 1.0), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let config = UpstreamConfig {
+            retry_max_delay_millis: 5000,
+            ..UpstreamConfig::default()
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, "2".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(2)));
+        assert_eq!(
+            retry_delay(&config, 1, retry_after(&headers)),
+            Some(Duration::from_secs(2))
+        );
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(0)));
+
+        assert_eq!(retry_delay(&config, 1, Some(Duration::from_secs(60))), None);
+    }
+
+    #[test]
+    fn test_circuit_opens_and_probes() {
+        let breaker = CircuitBreaker::new(&UpstreamConfig {
+            circuit_failure_threshold: 2,
+            circuit_open_secs: 10,
+            ..UpstreamConfig::default()
+        });
+        let start = Instant::now();
+
+        breaker.record("klik0001", false, start);
+        assert!(breaker.check("klik0001", start).is_ok());
+        breaker.record("klik0001", false, start);
+        assert!(breaker.check("klik0001", start).is_err());
+        assert!(breaker.check("klik0002", start).is_ok());
+
+        let later = start + Duration::from_secs(11);
+        assert!(breaker.check("klik0001", later).is_ok());
+        assert!(breaker.check("klik0001", later).is_err());
+        breaker.record("klik0001", false, later);
+        assert!(breaker.check("klik0001", later).is_err());
+
+        let even_later = later + Duration::from_secs(11);
+        assert!(breaker.check("klik0001", even_later).is_ok());
+        breaker.record("klik0001", true, even_later);
+        assert!(breaker.check("klik0001", even_later).is_ok());
+        assert!(breaker.check("klik0001", even_later).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idle_institutes_are_evicted() {
+        let limiter = limiter(1, 10);
+
+        let first = limiter.acquire("klik0001").await.unwrap();
+        drop(limiter.acquire("klik0002").await.unwrap());
+        let _third = limiter.acquire("klik0003").await.unwrap();
+        {
+            let per_institute = limiter.per_institute.lock().unwrap();
+            assert!(per_institute.contains_key("klik0001"));
+            assert!(!per_institute.contains_key("klik0002"));
+        }
+        drop(first);
+
+        let breaker = CircuitBreaker::new(&UpstreamConfig {
+            circuit_failure_threshold: 2,
+            circuit_open_secs: 10,
+            ..UpstreamConfig::default()
+        });
+        let start = Instant::now();
+        breaker.record("klik0001", false, start);
+        breaker.record("klik0002", false, start);
+        breaker.record("klik0002", false, start);
+
+        let later = start + Duration::from_secs(11);
+        breaker.record("klik0003", false, later);
+        {
+            let circuits = breaker.circuits.lock().unwrap();
+            assert!(!circuits.contains_key("klik0001"));
+            assert!(circuits.contains_key("klik0002"));
+        }
+
+        // An open circuit is forgotten once it wasn't probed for a while
+        breaker.record("klik0004", false, later + Duration::from_secs(10));
+        assert!(!breaker.circuits.lock().unwrap().contains_key("klik0002"));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_fails_fast() {
+        let limiter = limiter(1, 0);
+
+        match limiter.acquire("klik0001").await {
+            Err(KretaError::UpstreamQueueFull) => {}
+            other => panic!("Expected a full queue, got {:?}", other.map(|_| ())),
+        }
+    }
+}